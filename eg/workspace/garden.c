@@ -0,0 +1,7 @@
+#include <stdio.h>
+#include "planter.h"
+
+int main(void) {
+    printf("planted %d seeds\n", plant());
+    return 0;
+}
@@ -0,0 +1,6 @@
+#include "planter.h"
+#include "seeds.h"
+
+int plant(void) {
+    return seeds() / 2;
+}
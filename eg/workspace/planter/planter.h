@@ -0,0 +1,6 @@
+#ifndef PLANTER_H
+#define PLANTER_H
+
+int plant(void);
+
+#endif
@@ -0,0 +1,6 @@
+#ifndef SEEDS_H
+#define SEEDS_H
+
+int seeds(void);
+
+#endif
@@ -71,12 +71,20 @@ pub fn derive_unused_keys(item: TokenStream) -> TokenStream {
 
 #[derive(Clone)]
 struct EnvVar {
+    raw: bool,
     parts: Punctuated<Expr, Token![,]>,
 }
 
 impl Parse for EnvVar {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        // raw variables like CC don't get the CR_ prefix
+        let raw = input.peek(kw::raw);
+        if raw {
+            let _raw: kw::raw = input.parse()?;
+        }
+
         Ok(EnvVar {
+            raw,
             parts: Punctuated::parse_separated_nonempty(input)?,
         })
     }
@@ -90,6 +98,7 @@ struct EnvVarCall {
 
 mod kw {
     syn::custom_keyword!(doc);
+    syn::custom_keyword!(raw);
 }
 
 impl Parse for EnvVarCall {
@@ -109,7 +118,7 @@ impl Parse for EnvVarCall {
             .cloned()
             .collect::<Vec<_>>();
 
-        let Some(EnvVar { parts }) = vars.pop() else {
+        let Some(EnvVar { parts, .. }) = vars.pop() else {
             return Err(syn::Error::new(
                 input.span(),
                 "need at least a default value",
@@ -130,15 +139,33 @@ impl Parse for EnvVarCall {
 
 #[proc_macro]
 pub fn env_var(tokens: TokenStream) -> TokenStream {
+    let outer = parse_macro_input!(tokens as EnvVarCall);
+    expand_env_var(outer, quote::quote!(crate::get_env_or))
+}
+
+/// Like env_var!, but for values which aren't strings, using get_env_parsed. The default is
+/// the value itself, like `0` for a count.
+#[proc_macro]
+pub fn env_var_parsed(tokens: TokenStream) -> TokenStream {
+    let outer = parse_macro_input!(tokens as EnvVarCall);
+    expand_env_var(outer, quote::quote!(crate::get_env_parsed))
+}
+
+fn expand_env_var(outer: EnvVarCall, getter: proc_macro2::TokenStream) -> TokenStream {
     let mut var_exprs = Vec::new();
     let mut var_names = Vec::new();
-    let outer = parse_macro_input!(tokens as EnvVarCall);
     for inner in outer.vars.into_iter() {
-        let mut var_expr = quote::quote!("CR");
-        let mut var_name = String::from("CR");
+        let (mut var_expr, mut var_name) = if inner.raw {
+            (quote::quote!(""), String::new())
+        } else {
+            (quote::quote!("CR"), String::from("CR"))
+        };
+
         for expr in inner.parts {
-            var_expr = quote::quote!(#var_expr , "_");
-            var_name.push('_');
+            if !var_name.is_empty() {
+                var_expr = quote::quote!(#var_expr , "_");
+                var_name.push('_');
+            }
             match expr {
                 Expr::Lit(ExprLit { lit, .. }) => match lit {
                     Lit::Str(litstr) => {
@@ -176,22 +203,50 @@ pub fn env_var(tokens: TokenStream) -> TokenStream {
     }
 
     let env_var_dir = PathBuf::from("dist").join("env_vars");
+    let doc = outer.doc.as_deref().unwrap_or("Undocumented");
     for var in var_names.iter() {
-        std::fs::write(
-            env_var_dir.join(var),
-            outer
-                .doc
-                .as_ref()
-                .map(|s| s.as_str())
-                .unwrap_or("Undocumented"),
-        )
-        .expect("create file");
+        // env_var_docs! includes these files, so rewriting them would rebuild every time
+        let path = env_var_dir.join(var);
+        if std::fs::read_to_string(&path).ok().as_deref() != Some(doc) {
+            std::fs::write(path, doc).expect("create file");
+        }
     }
 
     let or = outer.default;
 
     quote::quote!(
-        crate::get_env_or(&[#(&[#var_exprs]),*], #or)
+        #getter(&[#(&[#var_exprs]),*], #or)
+    )
+    .into_token_stream()
+    .into()
+}
+
+/// The docs env_var! wrote to dist/env_vars, as a slice of (name, doc) sorted by name, so the
+/// binary doesn't need the source tree to describe its variables
+#[proc_macro]
+pub fn env_var_docs(_tokens: TokenStream) -> TokenStream {
+    let env_var_dir = PathBuf::from("dist")
+        .join("env_vars")
+        .canonicalize()
+        .expect("find env var docs");
+    let mut names = std::fs::read_dir(&env_var_dir)
+        .expect("read env var docs")
+        .map(|entry| {
+            entry
+                .expect("read env var docs")
+                .file_name()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let paths = names
+        .iter()
+        .map(|name| env_var_dir.join(name).display().to_string());
+
+    quote::quote!(
+        &[#((#names, include_str!(#paths))),*]
     )
     .into_token_stream()
     .into()
@@ -0,0 +1,102 @@
+use crate::{error::Error, project::Project};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Hashes of the command and inputs each object file was last compiled from, kept in the build
+/// directory so changing flags compiles files again
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    hashes: Mutex<BTreeMap<PathBuf, String>>,
+    /// Hashes of the files compiled during this build. Only these are written back, since
+    /// targets built with other compilers share the same cache file.
+    compiled: Mutex<BTreeMap<PathBuf, String>>,
+}
+
+impl HashCache {
+    /// Read the cache for a project. A missing or unreadable cache is empty, which just means
+    /// everything gets compiled again.
+    pub fn load(project: &Project) -> HashCache {
+        let path = project.build_dir.join(crate::HASH_CACHE_FILENAME);
+        HashCache {
+            hashes: Mutex::new(read_hashes(&path)),
+            compiled: Default::default(),
+            path,
+        }
+    }
+
+    pub fn is_up_to_date(&self, output_path: &Path, hash: &str) -> bool {
+        output_path.exists()
+            && self
+                .hashes
+                .lock()
+                .unwrap()
+                .get(output_path)
+                .is_some_and(|cached| cached == hash)
+    }
+
+    pub fn insert(&self, output_path: &Path, hash: String) {
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(output_path.to_owned(), hash.clone());
+        self.compiled
+            .lock()
+            .unwrap()
+            .insert(output_path.to_owned(), hash);
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(|io| Error::file_io(io, dir))?;
+        }
+
+        let mut hashes = read_hashes(&self.path);
+        hashes.extend(
+            self.compiled
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(path, hash)| (path.clone(), hash.clone())),
+        );
+
+        let hashes = serde_json::to_string_pretty(&hashes)
+            .map_err(|err| Error::Bug(format!("Could not serialize cache: {}", err)))?;
+        std::fs::write(&self.path, hashes).map_err(|io| Error::file_io(io, &self.path))?;
+        tracing::debug!("Wrote {}", self.path.display());
+        Ok(())
+    }
+}
+
+fn read_hashes(path: &Path) -> BTreeMap<PathBuf, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+            tracing::warn!("Ignoring invalid cache {}: {}", path.display(), err);
+            BTreeMap::new()
+        }),
+        Err(_) => BTreeMap::new(),
+    }
+}
+
+/// Hash a command line along with the contents of some files, or None if any can't be read
+pub fn hash_inputs<'p>(
+    command: &[String],
+    paths: impl IntoIterator<Item = &'p Path>,
+) -> Option<String> {
+    let mut hasher = blake3::Hasher::new();
+    for arg in command {
+        hasher.update(&(arg.len() as u64).to_le_bytes());
+        hasher.update(arg.as_bytes());
+    }
+    for path in paths {
+        let contents = std::fs::read(path).ok()?;
+        // the path is included so moving code between files counts as a change
+        hasher.update(path.as_os_str().as_encoded_bytes());
+        hasher.update(&(contents.len() as u64).to_le_bytes());
+        hasher.update(&contents);
+    }
+    Some(hasher.finalize().to_hex().to_string())
+}
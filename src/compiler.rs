@@ -1,16 +1,28 @@
 use crate::{
+    cache::{self, HashCache},
+    deps,
     error::Error,
-    project::{Project, Target},
+    project::{Profile, Project, Target, TargetType, Warnings},
+    BuildOptions, Incremental,
 };
+use indexmap::IndexMap;
 use std::{
+    collections::HashMap,
     ops::Deref,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
 #[derive(Debug)]
 pub struct Compiler {
     pub name: String,
     pub inner: CompilerInner,
+    pkg_config_cache: Mutex<HashMap<(String, &'static str), Vec<String>>>,
+    hash_cache: OnceLock<HashCache>,
 }
 
 impl Deref for Compiler {
@@ -22,157 +34,760 @@ impl Deref for Compiler {
 }
 
 #[derive(serde::Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct CompilerInner {
     pub compile_format: Vec<String>,
     pub compile_command: String,
     pub compile_verbose_flag: String,
     pub compile_debug_flag: String,
+    #[serde(default = "default_compile_optimization_option")]
+    pub compile_optimization_option: String,
     pub compile_only_flag: String,
     pub compile_include_path_option: String,
     pub compile_output_option: String,
     pub compile_output_format: String,
+    pub compile_cxx_command: Option<String>,
+    #[serde(default = "default_compile_define_option")]
+    pub compile_define_option: String,
+    #[serde(default = "default_compile_std_option")]
+    pub compile_std_option: String,
+    #[serde(default = "default_sanitize_option")]
+    pub sanitize_option: String,
+    #[serde(default = "default_compile_werror_flag")]
+    pub compile_werror_flag: String,
+    /// Program to run the compiler with, like ccache
+    #[serde(default)]
+    pub compile_wrapper: String,
+    #[serde(default = "default_cxx_extensions")]
+    pub cxx_extensions: Vec<String>,
 
     pub dynamic_link_format: Vec<String>,
     pub binary_link_format: Vec<String>,
     pub link_command: String,
+    /// Program to run the linker with
+    #[serde(default)]
+    pub link_wrapper: String,
     pub dynamic_link_flag: String,
     pub link_verbose_flag: String,
     pub link_debug_flag: String,
     pub link_library_path_option: String,
     pub link_output_option: String,
-    pub dynamic_link_output_format: String,
+    pub dynamic_link_output_format: PerOs,
     pub link_option: String,
+    /// Name of a needed library after link_option, like "%target.lib"
+    #[serde(default = "default_link_format")]
+    pub link_format: String,
+    #[serde(default = "default_link_framework_option")]
+    pub link_framework_option: String,
+    #[serde(default = "default_rpath_option")]
+    pub rpath_option: String,
 
     pub archive_command: String,
     pub archive_format: Vec<String>,
     pub archive_output_format: String,
     pub archive_verbose_flag: String,
     pub archive_flag: String,
+
+    #[serde(default)]
+    pub response_file_threshold: Option<usize>,
+    #[serde(default = "default_response_file_option")]
+    pub response_file_option: String,
+
+    // anything else is a typo, reported by load_compilers
+    #[serde(flatten)]
+    pub rest: HashMap<String, toml::Value>,
+}
+
+fn default_compile_optimization_option() -> String {
+    String::from("-O")
+}
+
+fn default_compile_define_option() -> String {
+    String::from("-D")
+}
+
+fn default_compile_std_option() -> String {
+    String::from("-std=")
+}
+
+fn default_sanitize_option() -> String {
+    String::from("-fsanitize=")
+}
+
+fn default_compile_werror_flag() -> String {
+    String::from("-Werror")
+}
+
+fn default_link_format() -> String {
+    String::from("%target")
+}
+
+fn default_link_framework_option() -> String {
+    String::from("-framework")
+}
+
+fn default_rpath_option() -> String {
+    String::from("-Wl,-rpath,")
+}
+
+fn default_cxx_extensions() -> Vec<String> {
+    ["cc", "cpp", "cxx", "c++", "C"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+fn default_response_file_option() -> String {
+    String::from("@")
+}
+
+/// Counts the steps of a build, shared by every worker, so each file compiled and each output
+/// archived or linked can be logged as `[n/total]`
+#[derive(Debug, Default)]
+pub struct Progress {
+    done: AtomicUsize,
+    total: usize,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Progress {
+        Progress {
+            done: AtomicUsize::new(0),
+            total,
+        }
+    }
+
+    /// Count a step, returning its `[n/total]` prefix
+    pub fn next(&self) -> String {
+        let n = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        format!("[{}/{}]", n, self.total)
+    }
+}
+
+/// A setting which is either the same everywhere, or a table keyed by `std::env::consts::OS`
+/// with an optional `other` fallback, like `{ macos = "lib%target.dylib", other = "lib%target.so" }`
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+pub enum PerOs {
+    All(String),
+    Os(HashMap<String, String>),
+}
+
+impl PerOs {
+    pub fn for_current_os(&self) -> Option<&str> {
+        match self {
+            PerOs::All(value) => Some(value),
+            PerOs::Os(values) => values
+                .get(std::env::consts::OS)
+                .or_else(|| values.get("other"))
+                .map(String::as_str),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct CompileCommand {
+    pub directory: PathBuf,
+    pub file: PathBuf,
+    pub arguments: Vec<String>,
+}
+
+/// What building a target did
+#[derive(serde::Serialize, Debug, Default)]
+pub struct TargetBuildResult {
+    pub name: String,
+    /// Sources which were compiled, or would have been with --dry-run
+    pub compiled: Vec<PathBuf>,
+    /// Sources which were already up to date
+    pub skipped: Vec<PathBuf>,
+    /// Archives, libraries, and binaries which were created
+    pub outputs: Vec<PathBuf>,
+    /// How long each compiled source and output took
+    pub timings: Vec<(PathBuf, Duration)>,
+}
+
+/// Everything that building a target would do, for --format json
+#[derive(serde::Serialize, Debug)]
+pub struct TargetPlan {
+    pub name: String,
+    pub types: Vec<String>,
+    /// The target's directory, where pre_build and post_build run
+    pub path: PathBuf,
+    pub pre_build: Vec<String>,
+    pub sources: Vec<PathBuf>,
+    pub objects: Vec<PathBuf>,
+    pub compile: Vec<Vec<String>>,
+    pub archive: Option<Vec<String>>,
+    pub dynamic_link: Option<Vec<String>>,
+    pub binary_link: Option<Vec<String>>,
+    pub post_build: Vec<String>,
+}
+
+/// A POSIX shell script which runs every command in `plan`, for --emit-script
+pub fn shell_script(plan: &[TargetPlan]) -> String {
+    let mut script = String::from("#!/bin/sh\nset -e\n");
+    for target in plan {
+        script.push_str(&format!("\n# {}\n", target.name));
+        let path = shell_quote(&target.path.display().to_string());
+        for command in target.pre_build.iter() {
+            script.push_str(&format!("(cd {} && {})\n", path, command));
+        }
+
+        let mut object_dirs = target
+            .objects
+            .iter()
+            .filter_map(|object| object.parent())
+            .collect::<Vec<_>>();
+        object_dirs.dedup();
+        for object_dir in object_dirs {
+            script.push_str(&format!(
+                "mkdir -p {}\n",
+                shell_quote(&object_dir.display().to_string())
+            ));
+        }
+
+        let commands = target
+            .compile
+            .iter()
+            .chain(target.archive.iter())
+            .chain(target.dynamic_link.iter())
+            .chain(target.binary_link.iter());
+        for command in commands {
+            script.push_str(&shell_command(command));
+            script.push('\n');
+        }
+
+        for command in target.post_build.iter() {
+            script.push_str(&format!("(cd {} && {})\n", path, command));
+        }
+    }
+    script
+}
+
+/// A command line as it would be typed into a shell
+fn shell_command(command: &[String]) -> String {
+    command
+        .iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.into()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
 }
 
 const PATH_SEPARATOR: &str = ",";
 
+/// Version reported for compilers which don't understand --version
+pub const UNKNOWN_VERSION: &str = "unknown";
+
 impl Compiler {
-    fn short_source_path(&self, project: &Project, source_path: &Path) -> Result<String, Error> {
-        Ok(source_path
+    fn load_compilers() -> Result<HashMap<String, CompilerInner>, Error> {
+        let compilers_path = crate::write_default_compilers_file()?;
+        let compilers_str = std::fs::read_to_string(compilers_path.as_path()).map_err(|io| {
+            if io.kind() == std::io::ErrorKind::NotFound {
+                Error::NoCompilersFile(compilers_path.display().to_string())
+            } else {
+                Error::file_io(io, compilers_path.as_path())
+            }
+        })?;
+
+        let compilers: HashMap<String, CompilerInner> =
+            toml::from_str(&compilers_str).map_err(|toml| Error::GenericToml {
+                toml: Box::new(toml),
+                path: compilers_path.display().to_string(),
+            })?;
+
+        for (name, inner) in compilers.iter() {
+            if inner.dynamic_link_output_format.for_current_os().is_none() {
+                return Err(Error::CompilerBroken {
+                    why: format!(
+                        "{} has no dynamic_link_output_format for {} in {}",
+                        name,
+                        std::env::consts::OS,
+                        compilers_path.display()
+                    ),
+                });
+            }
+
+            if !inner.rest.is_empty() {
+                let mut keys = inner.rest.keys().cloned().collect::<Vec<_>>();
+                keys.sort();
+                return Err(Error::UnknownCompilerKeys {
+                    compiler: name.clone(),
+                    keys,
+                    path: compilers_path.display().to_string(),
+                });
+            }
+        }
+
+        Ok(compilers)
+    }
+
+    /// Every compiler in compilers.toml, sorted by name
+    pub fn load_all() -> Result<Vec<Compiler>, Error> {
+        let mut compilers = Compiler::load_compilers()?
+            .into_iter()
+            .map(|(name, inner)| Compiler {
+                name,
+                inner,
+                pkg_config_cache: Default::default(),
+                hash_cache: Default::default(),
+            })
+            .collect::<Vec<_>>();
+        compilers.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(compilers)
+    }
+
+    pub fn from_name(name: &str) -> Result<Compiler, Error> {
+        let mut compilers = Compiler::load_compilers()?;
+
+        // duplicate names are rejected by the TOML parser, so there is at most one
+        let inner = compilers.remove(name).ok_or_else(|| {
+            let mut available = compilers.keys().cloned().collect::<Vec<_>>();
+            available.sort();
+            Error::NoCompiler {
+                name: name.into(),
+                available,
+            }
+        })?;
+
+        Ok(Compiler {
+            name: name.into(),
+            inner,
+            pkg_config_cache: Default::default(),
+            hash_cache: Default::default(),
+        })
+    }
+
+    /// The first line of `compile_command --version`, or UNKNOWN_VERSION if the compiler doesn't
+    /// support it
+    pub fn detect_version(&self) -> Result<String, Error> {
+        let capture = subprocess::Exec::cmd(&self.compile_command)
+            .arg("--version")
+            .stdout(subprocess::Redirection::Pipe)
+            .stderr(subprocess::NullFile)
+            .capture()?;
+
+        let output = capture.stdout_str();
+        match output.lines().map(str::trim).find(|line| !line.is_empty()) {
+            Some(version) if capture.success() => Ok(version.into()),
+            _ => Ok(UNKNOWN_VERSION.into()),
+        }
+    }
+
+    /// Make sure every compiler, linker, and archiver the targets will run can be found, so a
+    /// missing tool fails the build before anything is compiled
+    pub fn check_tools<'t>(
+        &self,
+        project: &Project,
+        targets: impl IntoIterator<Item = &'t Target>,
+    ) -> Result<(), Error> {
+        let mut tools = IndexMap::new();
+        for target in targets {
+            for source in target.sources.iter() {
+                let short_source_path = self.short_source_path(project, source);
+                let wrapper = self.resolve_compiler_wrapper(&short_source_path);
+                let tool = match wrapper.split_whitespace().next() {
+                    Some(wrapper) => wrapper.to_string(),
+                    None => self.resolve_compile_command(&short_source_path),
+                };
+                tools.entry(tool).or_insert("compiler");
+            }
+
+            for target_type in target.type_.iter() {
+                match target_type {
+                    TargetType::Archive => tools
+                        .entry(self.resolve_archive_command(&target.name))
+                        .or_insert("archiver"),
+                    TargetType::Dynamic | TargetType::Binary => {
+                        let wrapper = self.resolve_linker_wrapper(&target.name);
+                        let tool = match wrapper.split_whitespace().next() {
+                            Some(wrapper) => wrapper.to_string(),
+                            None => self.resolve_link_command(&target.name),
+                        };
+                        tools.entry(tool).or_insert("linker")
+                    }
+                    TargetType::HeaderOnly => continue,
+                };
+            }
+        }
+
+        for (tool, what) in tools {
+            // which handles absolute and relative paths as well as bare names on PATH
+            let path = which::which(&tool).map_err(|err| Error::CompilerBroken {
+                why: format!("{} {} for {} not found: {}", what, tool, self.name, err),
+            })?;
+            tracing::debug!("Using {} {}", what, path.display());
+        }
+
+        Ok(())
+    }
+
+    fn short_source_path(&self, project: &Project, source_path: &Path) -> String {
+        // sources outside the project dir were allowed by allow_external_paths
+        source_path
             .strip_prefix(&project.dir)
-            .map_err(|_| {
-                Error::Bug(format!(
-                    "Source file {} not in project dir {}",
-                    source_path.display(),
-                    project.dir.display(),
-                ))
-            })?
+            .unwrap_or(source_path)
             .display()
-            .to_string())
+            .to_string()
     }
 
-    pub fn compile_single_file<S: AsRef<Path>>(
+    fn is_up_to_date<S: AsRef<Path>>(
         &self,
         project: &Project,
         source_path: &Path,
         include_paths: &[S],
-        debug: bool,
-        verbose: bool,
-        dry_run: bool,
-    ) -> Result<(), Error> {
-        if !source_path.is_absolute() {
-            return Err(Error::Bug(format!(
-                "Compiling non-absolute source file {}",
-                source_path.display().to_string()
-            )));
+        output_path: &Path,
+    ) -> bool {
+        let modified = |path: &Path| path.metadata().and_then(|meta| meta.modified());
+
+        let Ok(output_modified) = modified(output_path) else {
+            return false;
+        };
+
+        let headers = deps::included_headers(source_path, include_paths);
+        let up_to_date = [source_path, project.file.as_path()]
+            .into_iter()
+            .chain(headers.iter().map(|header| header.as_path()))
+            .all(|input| {
+                modified(input).is_ok_and(|input_modified| input_modified <= output_modified)
+            });
+        up_to_date
+    }
+
+    /// Hash of the command compiling a source, along with everything is_up_to_date compares
+    /// against the object file
+    fn input_hash<S: AsRef<Path>>(
+        &self,
+        project: &Project,
+        source_path: &Path,
+        include_paths: &[S],
+        command: &[String],
+    ) -> Option<String> {
+        let headers = deps::included_headers(source_path, include_paths);
+        cache::hash_inputs(
+            command,
+            [source_path, project.file.as_path()]
+                .into_iter()
+                .chain(headers.iter().map(|header| header.as_path())),
+        )
+    }
+
+    fn hash_cache(&self, project: &Project) -> &HashCache {
+        self.hash_cache.get_or_init(|| HashCache::load(project))
+    }
+
+    /// Write the hashes of files compiled with --incremental hash
+    pub fn save_hash_cache(&self, options: &BuildOptions) -> Result<(), Error> {
+        match self.hash_cache.get() {
+            Some(hash_cache) if !options.dry_run => hash_cache.save(),
+            _ => Ok(()),
         }
+    }
 
-        let short_source_path = self.short_source_path(project, source_path)?;
-        tracing::info!("Compiling {}", short_source_path);
+    /// Build the command line which compiles a single source file
+    #[allow(clippy::too_many_arguments)]
+    pub fn compile_command_line<S: AsRef<Path>>(
+        &self,
+        project: &Project,
+        source_path: &Path,
+        include_paths: &[S],
+        flags: &[String],
+        target: &Target,
+        profile: &Profile,
+        options: &BuildOptions,
+    ) -> Result<Vec<String>, Error> {
+        let short_source_path = self.short_source_path(project, source_path);
+        let output_path = self.compile_output_filename(project, &short_source_path, source_path)?;
 
         let command_format = self.resolve_compile_command_format(&short_source_path);
         let compiler_command = self.resolve_compile_command(&short_source_path);
+        let compiler_wrapper = self.resolve_compiler_wrapper(&short_source_path);
         let compiler_verbose_flag = self.resolve_compiler_verbose_flag(&short_source_path);
         let compiler_debug_flag = self.resolve_compiler_debug_flag(&short_source_path);
+        let compiler_optimization_option =
+            self.resolve_compiler_optimization_option(&short_source_path);
         let compiler_include_path_option =
             self.resolve_compiler_include_path_option(&short_source_path);
         let compiler_compile_only_flag =
             self.resolve_compiler_compile_only_flag(&short_source_path);
         let compiler_output_option = self.resolve_compiler_output_option(&short_source_path);
+        let compiler_define_option = self.resolve_compiler_define_option(&short_source_path);
+        let compiler_std_option = self.resolve_compiler_std_option(&short_source_path);
+        let compiler_sanitize_option = self.resolve_compiler_sanitize_option(&short_source_path);
+        let compiler_werror_flag = self.resolve_compiler_werror_flag(&short_source_path);
         let compiler_include_paths = self.resolve_include_paths(&short_source_path, include_paths);
 
+        // C++ sources use cxx_std if the target has one
+        let std = if self.is_cxx_source(&short_source_path) {
+            target.cxx_std.as_deref().or(target.std.as_deref())
+        } else {
+            target.std.as_deref()
+        };
+
+        // the target's setting wins over --werror
+        let werror = match target.warnings {
+            Some(warnings) => warnings == Warnings::Error,
+            None => options.werror,
+        };
+
+        let defines = target
+            .defines
+            .iter()
+            .chain(profile.defines.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        let include_paths = compiler_include_paths
+            .split(PATH_SEPARATOR)
+            .filter(|path| !path.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        let scalars = [
+            ("%command", compiler_command.clone()),
+            (
+                "%verbose_flag",
+                flag_if(options.verbose, compiler_verbose_flag),
+            ),
+            ("%debug_flag", flag_if(profile.debug, compiler_debug_flag)),
+            (
+                "%optimization",
+                option_with(
+                    &compiler_optimization_option,
+                    profile.optimization.as_deref(),
+                ),
+            ),
+            ("%std", option_with(&compiler_std_option, std)),
+            (
+                "%sanitize",
+                sanitize_flag(&compiler_sanitize_option, &options.sanitize),
+            ),
+            ("%werror", flag_if(werror, compiler_werror_flag)),
+            ("%compile_only_flag", compiler_compile_only_flag),
+            ("%source", source_path.display().to_string()),
+            ("%output_option", compiler_output_option),
+            ("%output", output_path.display().to_string()),
+        ];
+        let lists = [
+            ("%flags", flags.to_vec()),
+            ("%defines", defines.clone()),
+            ("%includes", include_paths.clone()),
+        ];
+
         let mut command = Vec::<String>::new();
         for part in command_format.split(" ") {
             match part {
-                "%command" => command.push(compiler_command.clone()),
-                "%verbose_flag" if verbose => command.push(compiler_verbose_flag.clone()),
-                "%verbose_flag" if !verbose => {}
-                "%debug_flag" if debug => command.push(compiler_debug_flag.clone()),
-                "%debug_flag" if !debug => {}
-                "%compile_only_flag" => command.push(compiler_compile_only_flag.clone()),
+                "%command" => {
+                    command.extend(compiler_wrapper.split_whitespace().map(String::from));
+                    command.push(compiler_command.clone());
+                }
+                "%flags" => command.extend(flags.iter().cloned()),
+                "%defines" => {
+                    for define in defines.iter() {
+                        command.push(compiler_define_option.clone());
+                        command.push(define.clone());
+                    }
+                }
                 "%includes" => {
-                    for path in compiler_include_paths.split(PATH_SEPARATOR) {
-                        if path != "" {
-                            command.push(compiler_include_path_option.clone());
-                            command.push(path.into());
-                        }
+                    for path in include_paths.iter() {
+                        command.push(compiler_include_path_option.clone());
+                        command.push(path.clone());
                     }
                 }
-                "%source" => command.push(source_path.display().to_string()),
-                "%output_option" => command.push(compiler_output_option.clone()),
-                "%output" => command.push(
-                    self.compile_output_filename(&short_source_path, source_path)?
-                        .display()
-                        .to_string(),
-                ),
-                _ if part.starts_with("%") => return Err(Error::UnknownSubstitution(part.into())),
+                _ if part.contains("%") => command.extend(substitute(part, &scalars, &lists)?),
                 _ => command.push(part.into()),
             }
         }
 
+        // flags a toolchain doesn't have are configured as empty strings
+        command.retain(|part| !part.is_empty());
+        Ok(command)
+    }
+
+    /// Compile a source file, returning false if it was already up to date
+    #[allow(clippy::too_many_arguments)]
+    pub fn compile_single_file<S: AsRef<Path>>(
+        &self,
+        project: &Project,
+        source_path: &Path,
+        include_paths: &[S],
+        flags: &[String],
+        target: &Target,
+        profile: &Profile,
+        options: &BuildOptions,
+        progress: &Progress,
+    ) -> Result<bool, Error> {
+        if !source_path.is_absolute() {
+            return Err(Error::Bug(format!(
+                "Compiling non-absolute source file {}",
+                source_path.display()
+            )));
+        }
+
+        let short_source_path = self.short_source_path(project, source_path);
+        let output_path = self.compile_output_filename(project, &short_source_path, source_path)?;
+        let command = self.compile_command_line(
+            project,
+            source_path,
+            include_paths,
+            flags,
+            target,
+            profile,
+            options,
+        )?;
+        let env = self.command_env(project, target, options);
+
+        // an object built with different flags or environment is out of date no matter how old
+        // its inputs are, so the command is always part of the hash
+        let recorded_command = command
+            .iter()
+            .cloned()
+            .chain(env.iter().map(|(key, value)| format!("{}={}", key, value)))
+            .collect::<Vec<_>>();
+        let hash = match options.incremental {
+            Incremental::Mtime => cache::hash_inputs(&recorded_command, []),
+            Incremental::Hash => {
+                self.input_hash(project, source_path, include_paths, &recorded_command)
+            }
+        };
+        let up_to_date = hash
+            .as_deref()
+            .is_some_and(|hash| self.hash_cache(project).is_up_to_date(&output_path, hash))
+            && match options.incremental {
+                Incremental::Mtime => {
+                    self.is_up_to_date(project, source_path, include_paths, &output_path)
+                }
+                Incremental::Hash => true,
+            };
+        let step = progress.next();
+        if !options.force && up_to_date {
+            tracing::debug!("{} {} is up to date", step, short_source_path);
+            return Ok(false);
+        }
+
+        tracing::info!("{} Compiling {}", step, short_source_path);
+
         tracing::info!("{:?}", command);
-        if dry_run {
+        if options.dry_run {
             tracing::debug!("Skipping due to --dry-run");
-            return Ok(());
+            return Ok(true);
         }
-        let status = subprocess::Exec::cmd(&command[0])
-            .args(&command[1..])
-            .join()?;
-        if !status.success() {
-            Err(Error::CompilationFailed)
-        } else {
-            Ok(())
+
+        if let Some(object_dir) = output_path.parent() {
+            std::fs::create_dir_all(object_dir).map_err(|io| Error::file_io(io, object_dir))?;
+        }
+
+        if let Some(failure) = run_captured(&command, &short_source_path, &env)? {
+            return Err(Error::CompilationFailed {
+                source_file: short_source_path,
+                command: shell_command(&command),
+                status: failure.status,
+                output: failure.output,
+            });
+        }
+
+        if let Some(hash) = hash {
+            self.hash_cache(project).insert(&output_path, hash);
         }
+        Ok(true)
+    }
+
+    /// The object files compiling a target produces, in the order of its sources. Archiving,
+    /// linking, and cleaning use this so they find exactly what compile_single_file wrote.
+    fn object_filenames(&self, project: &Project, target: &Target) -> Result<Vec<PathBuf>, Error> {
+        target
+            .sources
+            .iter()
+            .map(|source_path| {
+                let short_source_path = self.short_source_path(project, source_path);
+                self.compile_output_filename(project, &short_source_path, source_path)
+            })
+            .collect()
     }
 
     fn compile_output_filename(
         &self,
-        short_source_path: &String,
+        project: &Project,
+        short_source_path: &str,
         source_path: &Path,
     ) -> Result<PathBuf, Error> {
-        // hnngn wh
+        let source_stem = source_path.file_stem().ok_or_else(|| {
+            tracing::error!("Cannot not compile file without filename");
+            Error::NoFilename(source_path.display().to_string())
+        })?;
+
+        // objects mirror the source tree inside the build dir. sources from outside the project
+        // (see allow_external_paths) are mirrored by their absolute path under external/
+        let source_dir = source_path.parent().unwrap_or(Path::new(""));
+        let object_dir = match source_dir.strip_prefix(&project.dir) {
+            Ok(relative) => project.build_dir.join(relative),
+            Err(_) => project.build_dir.join("external").join(
+                source_dir
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            ),
+        };
+
         Ok(PathBuf::from(
             self.resolve_compiler_output_format(short_source_path)
                 .replace(
                     "%source_basename",
-                    &source_path
-                        .with_file_name(source_path.file_stem().ok_or_else(|| {
-                            tracing::error!("Cannot not compile file without filename");
-                            Error::NoFilename(source_path.display().to_string())
-                        })?)
-                        .display()
-                        .to_string(),
+                    &object_dir.join(source_stem).display().to_string(),
                 ),
         ))
     }
 
-    fn resolve_compile_command(&self, source_file: &String) -> String {
+    fn is_cxx_source(&self, source_file: &str) -> bool {
+        Path::new(source_file).extension().is_some_and(|extension| {
+            self.cxx_extensions
+                .iter()
+                .any(|cxx_extension| extension == cxx_extension.as_str())
+        })
+    }
+
+    fn resolve_compile_command(&self, source_file: &str) -> String {
+        if let Some(cxx_command) = self.compile_cxx_command.as_deref() {
+            if self.is_cxx_source(source_file) {
+                return self.resolve_compile_cxx_command(source_file, cxx_command);
+            }
+        }
+
         macros::env_var!(
-            doc "Command used to compile a source file"
+            doc "Command used to compile a source file. Checked in order: CR_COMPILER_[source_file]_COMMAND, CR_COMPILER_COMMAND, CC, then compile_command from compilers.toml"
             "compiler", source_file, "command";
             "compiler_command";
+            raw "cc";
             self.compile_command.as_str()
         )
     }
 
-    fn resolve_compile_command_format(&self, source_file: &String) -> String {
+    fn resolve_compile_cxx_command(&self, source_file: &str, cxx_command: &str) -> String {
+        macros::env_var!(
+            doc "Command used to compile a C++ source file. Checked in order: CR_COMPILER_[source_file]_CXX_COMMAND, CR_COMPILER_CXX_COMMAND, CXX, then compile_cxx_command from compilers.toml"
+            "compiler", source_file, "cxx_command";
+            "compiler_cxx_command";
+            raw "cxx";
+            cxx_command
+        )
+    }
+
+    fn resolve_compiler_wrapper(&self, source_file: &str) -> String {
+        macros::env_var!(
+            doc "Program and arguments to put before the compile command, like ccache"
+            "compiler", source_file, "wrapper";
+            "compiler_wrapper";
+            self.compile_wrapper.as_str()
+        )
+    }
+
+    fn resolve_compile_command_format(&self, source_file: &str) -> String {
         macros::env_var!(
             doc "Format string used to build the command which will compile a source file"
             "compiler", source_file, "command_format";
@@ -181,7 +796,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_compiler_verbose_flag(&self, source_file: &String) -> String {
+    fn resolve_compiler_verbose_flag(&self, source_file: &str) -> String {
         macros::env_var!(
             doc "Flag which will cause the compiler to output verbose information"
             "compiler", source_file, "verbose_flag";
@@ -190,7 +805,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_compiler_debug_flag(&self, source_file: &String) -> String {
+    fn resolve_compiler_debug_flag(&self, source_file: &str) -> String {
         macros::env_var!(
             doc "Flag which will cause the compiler to include debug symbols"
             "compiler", source_file, "debug_flag";
@@ -199,7 +814,16 @@ impl Compiler {
         )
     }
 
-    fn resolve_compiler_include_path_option(&self, source_file: &String) -> String {
+    fn resolve_compiler_optimization_option(&self, source_file: &str) -> String {
+        macros::env_var!(
+            doc "Option which sets the compiler's optimization level, e.g. -O for -O2"
+            "compiler", source_file, "optimization_option";
+            "compiler_optimization_option";
+            self.compile_optimization_option.as_str()
+        )
+    }
+
+    fn resolve_compiler_include_path_option(&self, source_file: &str) -> String {
         macros::env_var!(
             doc "Option used to specify a path to search for header files"
             "compiler", source_file, "include_path_option";
@@ -208,7 +832,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_compiler_compile_only_flag(&self, source_file: &String) -> String {
+    fn resolve_compiler_compile_only_flag(&self, source_file: &str) -> String {
         macros::env_var!(
             doc "Flag used to compile a source file without linking it"
             "compiler", source_file, "compile_only_flag";
@@ -217,7 +841,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_compiler_output_option(&self, source_file: &String) -> String {
+    fn resolve_compiler_output_option(&self, source_file: &str) -> String {
         macros::env_var!(
             doc "Option used to specify the output location of a compiled source file"
             "compiler", source_file, "output_option";
@@ -226,7 +850,43 @@ impl Compiler {
         )
     }
 
-    fn resolve_compiler_output_format(&self, source_file: &String) -> String {
+    fn resolve_compiler_define_option(&self, source_file: &str) -> String {
+        macros::env_var!(
+            doc "Option used to define a preprocessor macro"
+            "compiler", source_file, "define_option";
+            "compiler_define_option";
+            self.compile_define_option.as_str()
+        )
+    }
+
+    fn resolve_compiler_sanitize_option(&self, source_file: &str) -> String {
+        macros::env_var!(
+            doc "Option used to enable sanitizers when compiling, followed by the names given to --sanitize"
+            "compiler", source_file, "sanitize_option";
+            "compiler_sanitize_option";
+            self.sanitize_option.as_str()
+        )
+    }
+
+    fn resolve_compiler_werror_flag(&self, source_file: &str) -> String {
+        macros::env_var!(
+            doc "Flag which will cause the compiler to treat warnings as errors"
+            "compiler", source_file, "werror_flag";
+            "compiler_werror_flag";
+            self.compile_werror_flag.as_str()
+        )
+    }
+
+    fn resolve_compiler_std_option(&self, source_file: &str) -> String {
+        macros::env_var!(
+            doc "Option used to select the language standard, followed by the target's std or cxx_std"
+            "compiler", source_file, "std_option";
+            "compiler_std_option";
+            self.compile_std_option.as_str()
+        )
+    }
+
+    fn resolve_compiler_output_format(&self, source_file: &str) -> String {
         macros::env_var!(
             doc "Format that a compiled source file should take"
             "compiler", source_file, "output_format";
@@ -237,7 +897,7 @@ impl Compiler {
 
     fn resolve_include_paths<S: AsRef<Path>>(
         &self,
-        source_file: &String,
+        source_file: &str,
         include_paths: &[S],
     ) -> String {
         macros::env_var!(
@@ -252,7 +912,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_link_command(&self, target_name: &String) -> String {
+    fn resolve_link_command(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Command used to link a dynamic library"
             "linker", target_name, "command";
@@ -261,7 +921,16 @@ impl Compiler {
         )
     }
 
-    fn resolve_dynamic_link_command_format(&self, target_name: &String) -> String {
+    fn resolve_linker_wrapper(&self, target_name: &str) -> String {
+        macros::env_var!(
+            doc "Program and arguments to put before the link command"
+            "linker", target_name, "wrapper";
+            "linker_wrapper";
+            self.link_wrapper.as_str()
+        )
+    }
+
+    fn resolve_dynamic_link_command_format(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Format string used to build the command which will link a dynamic library"
             "dynamic_linker", target_name, "command_format";
@@ -270,7 +939,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_binary_link_command_format(&self, target_name: &String) -> String {
+    fn resolve_binary_link_command_format(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Format string used to build the command which will link a binary"
             "linker", target_name, "command_format";
@@ -279,7 +948,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_linker_verbose_flag(&self, target_name: &String) -> String {
+    fn resolve_linker_verbose_flag(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Flag which will cause the linker to output verbose information"
             "linker", target_name, "verbose_flag";
@@ -288,7 +957,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_linker_debug_flag(&self, target_name: &String) -> String {
+    fn resolve_linker_debug_flag(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Flag which will cause the linker to include debug symbols"
             "linker", target_name, "debug_flag";
@@ -297,7 +966,16 @@ impl Compiler {
         )
     }
 
-    fn resolve_linker_link_path_option(&self, target_name: &String) -> String {
+    fn resolve_linker_sanitize_option(&self, target_name: &str) -> String {
+        macros::env_var!(
+            doc "Option used to enable sanitizers when linking, followed by the names given to --sanitize"
+            "linker", target_name, "sanitize_option";
+            "linker_sanitize_option";
+            self.sanitize_option.as_str()
+        )
+    }
+
+    fn resolve_linker_link_path_option(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Option used to specify a path to search for library files"
             "linker", target_name, "library_path_option";
@@ -306,7 +984,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_linker_output_option(&self, target_name: &String) -> String {
+    fn resolve_linker_output_option(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Option used to specify the output location of a linked target"
             "linker", target_name, "output_option";
@@ -315,16 +993,17 @@ impl Compiler {
         )
     }
 
-    fn resolve_linker_dynamic_output_format(&self, target_name: &String) -> String {
+    fn resolve_linker_dynamic_output_format(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Format that a linked dynamic target should take"
             "linker", target_name, "dynamic_output_format";
             "linker_dynamic_output_format";
-            self.dynamic_link_output_format.as_str()
+            // checked by load_compilers
+            self.dynamic_link_output_format.for_current_os().unwrap_or_default()
         )
     }
 
-    fn resolve_linker_dynamic_link_flag(&self, target_name: &String) -> String {
+    fn resolve_linker_dynamic_link_flag(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Flag that will cause the linker to output a dynamic library"
             "linker_dynamic", target_name, "link_flag";
@@ -333,7 +1012,7 @@ impl Compiler {
         )
     }
 
-    fn resolve_linker_link_option(&self, target_name: &String) -> String {
+    fn resolve_linker_link_option(&self, target_name: &str) -> String {
         macros::env_var!(
             doc "Option that will include a libary in a link command"
             "linker", target_name, "link_option";
@@ -342,11 +1021,44 @@ impl Compiler {
         )
     }
 
-    fn resolve_linker_paths<S: AsRef<Path>>(
-        &self,
-        target_name: &String,
-        link_paths: &[S],
-    ) -> String {
+    fn resolve_linker_link_format(&self, target_name: &str) -> String {
+        macros::env_var!(
+            doc "Format of a library's name in a link command, after the link option"
+            "linker", target_name, "link_format";
+            "linker_link_format";
+            self.link_format.as_str()
+        )
+    }
+
+    /// The argument which links a needed library, like "-lgrow" or "grow.lib"
+    fn link_library(&self, need: &str) -> String {
+        format!(
+            "{}{}",
+            self.resolve_linker_link_option(need),
+            self.resolve_linker_link_format(need)
+                .replace("%target", need)
+        )
+    }
+
+    fn resolve_linker_framework_option(&self, target_name: &str) -> String {
+        macros::env_var!(
+            doc "Option that will link a framework on macOS"
+            "linker", target_name, "framework_option";
+            "linker_framework_option";
+            self.link_framework_option.as_str()
+        )
+    }
+
+    fn resolve_linker_rpath_option(&self, target_name: &str) -> String {
+        macros::env_var!(
+            doc "Option that adds a runtime library search path to a binary, for targets with rpath"
+            "linker", target_name, "rpath_option";
+            "linker_rpath_option";
+            self.rpath_option.as_str()
+        )
+    }
+
+    fn resolve_linker_paths<S: AsRef<Path>>(&self, target_name: &str, link_paths: &[S]) -> String {
         macros::env_var!(
             doc "Comma-separated list of paths to search for library files"
             "linker", target_name, "link_paths";
@@ -359,325 +1071,1148 @@ impl Compiler {
         )
     }
 
+    fn resolve_pkg_config_command(&self) -> String {
+        macros::env_var!(
+            doc "Command used to look up the flags for pkg-config packages"
+            "pkg_config_command";
+            "pkg-config"
+        )
+    }
+
+    /// Run pkg-config for a package, reusing the answer from earlier targets in the build
+    fn pkg_config(&self, package: &str, query: &'static str) -> Result<Vec<String>, Error> {
+        let key = (package.to_string(), query);
+        if let Some(flags) = self.pkg_config_cache.lock().unwrap().get(&key) {
+            return Ok(flags.clone());
+        }
+
+        let pkg_config = self.resolve_pkg_config_command();
+        let output = subprocess::Exec::cmd(&pkg_config)
+            .args(&[query, package])
+            .stdout(subprocess::Redirection::Pipe)
+            .stderr(subprocess::Redirection::Pipe)
+            .capture()
+            .map_err(|err| Error::PkgConfig {
+                package: package.into(),
+                why: format!("could not run {}: {}", pkg_config, err),
+            })?;
+
+        if !output.success() {
+            return Err(Error::PkgConfig {
+                package: package.into(),
+                why: output.stderr_str().trim().into(),
+            });
+        }
+
+        let flags = output
+            .stdout_str()
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        tracing::debug!("pkg-config {} {}: {:?}", query, package, flags);
+
+        self.pkg_config_cache
+            .lock()
+            .unwrap()
+            .insert(key, flags.clone());
+        Ok(flags)
+    }
+
+    fn compile_flags(&self, target: &Target) -> Result<Vec<String>, Error> {
+        let mut flags = target.cflags.clone();
+        for package in target.pkg_config.iter() {
+            flags.extend(self.pkg_config(package, "--cflags")?);
+        }
+        Ok(flags)
+    }
+
+    /// Paths of the dynamic libraries a binary needs, so it can be run without installing them
+    fn rpaths(&self, project: &Project, target: &Target) -> Vec<String> {
+        let rpath_option = self.resolve_linker_rpath_option(&target.name);
+        if !target.rpath || rpath_option.is_empty() {
+            return Vec::new();
+        }
+
+        target
+            .needs
+            .iter()
+            .filter_map(|need| project.target.get(need))
+            .filter(|need| need.type_.contains(&TargetType::Dynamic))
+            .map(|need| format!("{}{}", rpath_option, need.path.display()))
+            .collect()
+    }
+
+    /// Frameworks only exist on macOS, so elsewhere they're ignored
+    fn frameworks(&self, target: &Target) -> Vec<String> {
+        if !cfg!(target_os = "macos") {
+            return Vec::new();
+        }
+
+        let framework_option = self.resolve_linker_framework_option(&target.name);
+        target
+            .frameworks
+            .iter()
+            .flat_map(|framework| [framework_option.clone(), framework.clone()])
+            .collect()
+    }
+
+    fn pkg_config_libs(&self, target: &Target) -> Result<Vec<String>, Error> {
+        let mut libs = Vec::new();
+        for package in target.pkg_config.iter() {
+            libs.extend(self.pkg_config(package, "--libs")?);
+        }
+        Ok(libs)
+    }
+
+    /// The directories of a target and the targets it needs, used as include and link paths.
+    /// Needs which share a directory only appear once.
+    fn target_paths<'p>(
+        &self,
+        project: &'p Project,
+        target: &'p Target,
+    ) -> Result<Vec<&'p Path>, Error> {
+        let mut paths = vec![target.path.as_path()];
+        for need in target.needs.iter() {
+            let path = project
+                .target
+                .get(need.as_str())
+                .ok_or_else(|| Error::Bug("Resolved project had unknown target".into()))?
+                .path
+                .as_path();
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Where to look for libraries: like target_paths, without header-only targets since there's
+    /// nothing in them to link
+    fn link_paths<'p>(
+        &self,
+        project: &'p Project,
+        target: &'p Target,
+    ) -> Result<Vec<&'p Path>, Error> {
+        let mut paths = vec![target.path.as_path()];
+        for need in target.needs.iter() {
+            let need = project
+                .target
+                .get(need.as_str())
+                .ok_or_else(|| Error::Bug("Resolved project had unknown target".into()))?;
+            if !need.type_.contains(&TargetType::HeaderOnly)
+                && !paths.contains(&need.path.as_path())
+            {
+                paths.push(need.path.as_path());
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Where to look for headers: the target's paths, plus the include_dirs of the target and
+    /// the targets it needs
+    fn include_paths<'p>(
+        &self,
+        project: &'p Project,
+        target: &'p Target,
+    ) -> Result<Vec<&'p Path>, Error> {
+        let mut include_paths = self.target_paths(project, target)?;
+        let needs = target
+            .needs
+            .iter()
+            .filter_map(|need| project.target.get(need.as_str()));
+        for include_dir in std::iter::once(target)
+            .chain(needs)
+            .flat_map(|target| target.include_dirs.iter())
+        {
+            if !include_paths.contains(&include_dir.as_path()) {
+                include_paths.push(include_dir);
+            }
+        }
+        Ok(include_paths)
+    }
+
+    /// Describe how every source of `targets` is compiled, for compile_commands.json
+    pub fn compile_commands(
+        &self,
+        project: &Project,
+        targets: &[(&str, &Target)],
+        profile: &Profile,
+        options: &BuildOptions,
+    ) -> Result<Vec<CompileCommand>, Error> {
+        // tools reading compile_commands.json don't want verbose output
+        let options = BuildOptions {
+            verbose: false,
+            ..options.clone()
+        };
+
+        let mut commands = Vec::new();
+        for (_, target) in targets {
+            let include_paths = self.include_paths(project, target)?;
+            let flags = self.compile_flags(target)?;
+            for source in target.sources.iter() {
+                commands.push(CompileCommand {
+                    directory: project.dir.clone(),
+                    file: source.clone(),
+                    arguments: self.compile_command_line(
+                        project,
+                        source,
+                        &include_paths,
+                        &flags,
+                        target,
+                        profile,
+                        &options,
+                    )?,
+                });
+            }
+        }
+        Ok(commands)
+    }
+
+    /// Describe how `targets` would be built, in order
+    pub fn build_plan(
+        &self,
+        project: &Project,
+        targets: &[(&str, &Target)],
+        profile: &Profile,
+        options: &BuildOptions,
+    ) -> Result<Vec<TargetPlan>, Error> {
+        let mut plan = Vec::new();
+        for (_, target) in targets {
+            let include_paths = self.include_paths(project, target)?;
+            let flags = self.compile_flags(target)?;
+
+            let objects = self.object_filenames(project, target)?;
+            let mut compile = Vec::new();
+            for source in target.sources.iter() {
+                compile.push(self.compile_command_line(
+                    project,
+                    source,
+                    &include_paths,
+                    &flags,
+                    target,
+                    profile,
+                    options,
+                )?);
+            }
+
+            let mut types = target
+                .type_
+                .iter()
+                .map(|type_| type_.to_string())
+                .collect::<Vec<_>>();
+            types.sort();
+
+            plan.push(TargetPlan {
+                name: target.name.clone(),
+                types,
+                path: target.path.clone(),
+                pre_build: self.build_commands(target, &target.pre_build),
+                sources: target.sources.clone(),
+                objects,
+                compile,
+                archive: if target.type_.contains(&TargetType::Archive) {
+                    Some(self.archive_command_line(project, target, options)?)
+                } else {
+                    None
+                },
+                dynamic_link: if target.type_.contains(&TargetType::Dynamic) {
+                    Some(self.dynamic_link_command_line(project, target, profile, options)?)
+                } else {
+                    None
+                },
+                binary_link: if target.type_.contains(&TargetType::Binary) {
+                    Some(self.binary_link_command_line(project, target, profile, options)?)
+                } else {
+                    None
+                },
+                post_build: self.build_commands(target, &target.post_build),
+            });
+        }
+        Ok(plan)
+    }
+
+    /// Compile every source in a target, `jobs` at a time
     pub fn compile_target(
         &self,
         project: &Project,
         target: &Target,
-        debug: bool,
-        verbose: bool,
-        dry_run: bool,
-    ) -> Result<(), Error> {
-        for source in target.sources.iter() {
-            let mut include_paths = vec![target.path.as_path()];
-            for need in target.needs.iter() {
-                include_paths.push(
-                    project
-                        .target
-                        .get(need.as_str())
-                        .ok_or_else(|| Error::Bug(format!("Resolved project had unknown target")))?
-                        .path
-                        .as_path(),
-                );
+        profile: &Profile,
+        options: &BuildOptions,
+        jobs: usize,
+        progress: &Progress,
+    ) -> Result<TargetBuildResult, Error> {
+        if target.type_.contains(&TargetType::HeaderOnly) {
+            tracing::debug!("Nothing to compile for header-only target {}", target.name);
+            return Ok(TargetBuildResult {
+                name: target.name.clone(),
+                ..Default::default()
+            });
+        }
+
+        let include_paths = self.include_paths(project, target)?;
+        let flags = self.compile_flags(target)?;
+
+        let jobs = jobs.clamp(1, target.sources.len().max(1));
+        tracing::debug!("Compiling {} with {} jobs", target.name, jobs);
+
+        // each worker takes the next source until they run out or one fails
+        let next_source = AtomicUsize::new(0);
+        let first_error = Mutex::new(None);
+        let result = Mutex::new(TargetBuildResult {
+            name: target.name.clone(),
+            ..Default::default()
+        });
+        std::thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| loop {
+                    if first_error.lock().unwrap().is_some() {
+                        break;
+                    }
+
+                    let Some(source) = target
+                        .sources
+                        .get(next_source.fetch_add(1, Ordering::SeqCst))
+                    else {
+                        break;
+                    };
+
+                    let start = Instant::now();
+                    match self.compile_single_file(
+                        project,
+                        source,
+                        &include_paths,
+                        &flags,
+                        target,
+                        profile,
+                        options,
+                        progress,
+                    ) {
+                        Ok(true) => {
+                            let mut result = result.lock().unwrap();
+                            result.compiled.push(source.clone());
+                            result.timings.push((source.clone(), start.elapsed()));
+                        }
+                        Ok(false) => result.lock().unwrap().skipped.push(source.clone()),
+                        Err(err) => {
+                            first_error.lock().unwrap().get_or_insert(err);
+                            break;
+                        }
+                    }
+                });
             }
+        });
 
-            self.compile_single_file(project, source, &include_paths, debug, verbose, dry_run)?;
+        if let Some(err) = first_error.into_inner().unwrap() {
+            return Err(err);
         }
 
-        Ok(())
+        // the workers finish in any order
+        let mut result = result.into_inner().unwrap();
+        result.compiled.sort();
+        result.skipped.sort();
+        result.timings.sort();
+        Ok(result)
     }
 
-    fn resolve_archive_command(&self, target_name: &String) -> String {
+    fn resolve_archive_command(&self, target_name: &str) -> String {
         macros::env_var!(
+            doc "Command used to create a static library"
             "archive", target_name, "command";
             "archive_command";
             self.archive_command.as_str()
         )
     }
 
-    fn resolve_archive_format(&self, target_name: &String) -> String {
+    fn resolve_archive_format(&self, target_name: &str) -> String {
         macros::env_var!(
+            doc "Format string used to build the command which will create a static library"
             "archive", target_name, "format";
             "archive_format";
             &self.archive_format.join(" ")
         )
     }
 
-    fn resolve_archive_output_format(&self, target_name: &String) -> String {
+    fn resolve_archive_output_format(&self, target_name: &str) -> String {
         macros::env_var!(
+            doc "Format of the static library's filename"
             "archive", target_name, "output_format";
             "archive_output_format";
             self.archive_output_format.as_str()
         )
     }
 
-    fn resolve_archive_verbose_flag(&self, target_name: &String) -> String {
+    fn resolve_archive_verbose_flag(&self, target_name: &str) -> String {
         macros::env_var!(
+            doc "Flag which will cause the archiver to output verbose information"
             "archive", target_name, "verbose_flag";
             "archive_verbose_flag";
             self.archive_verbose_flag.as_str()
         )
     }
 
-    fn resolve_archive_flag(&self, target_name: &String) -> String {
+    fn resolve_archive_flag(&self, target_name: &str) -> String {
         macros::env_var!(
+            doc "Flag which tells the archiver to create a static library"
             "archive", target_name, "flag";
             "archive_flag";
             self.archive_flag.as_str()
         )
     }
 
-    pub fn create_archive(
+    /// Where use_response_file puts the arguments for one step of building a target
+    fn response_filename(&self, project: &Project, target: &Target, step: &str) -> PathBuf {
+        project
+            .build_dir
+            .join(format!("{}.{}.rsp", target.name, step))
+    }
+
+    /// How many arguments at the start of a link command name the program: the linker and
+    /// anything wrapping it
+    fn linker_program_len(&self, target_name: &str) -> usize {
+        self.resolve_linker_wrapper(target_name)
+            .split_whitespace()
+            .count()
+            + 1
+    }
+
+    /// If the command line is longer than response_file_threshold, move its arguments into a
+    /// response file in the build directory and pass that to the program instead. The first
+    /// `program_len` arguments, the program and any wrapper, stay on the command line.
+    fn use_response_file(
         &self,
         project: &Project,
         target: &Target,
-        verbose: bool,
+        step: &str,
+        command: Vec<String>,
+        program_len: usize,
         dry_run: bool,
+    ) -> Result<Vec<String>, Error> {
+        let Some(threshold) = self.response_file_threshold else {
+            return Ok(command);
+        };
+        let threshold = macros::env_var_parsed!(
+            doc "Length in bytes above which commands are passed to the program in a response file, if the compiler supports them"
+            "response_file_threshold";
+            threshold
+        );
+
+        let length = command.iter().map(|part| part.len() + 1).sum::<usize>();
+        if length <= threshold {
+            return Ok(command);
+        }
+
+        let response_file = self.response_filename(project, target, step);
+        tracing::debug!(
+            "Command is {} bytes, using response file {}",
+            length,
+            response_file.display()
+        );
+
+        let (program, args) = command.split_at(program_len.min(command.len()));
+        if !dry_run {
+            let contents = args
+                .iter()
+                .map(|part| quote_response_file_arg(part))
+                .collect::<Vec<_>>()
+                .join("\n");
+            std::fs::create_dir_all(&project.build_dir)
+                .map_err(|io| Error::file_io(io, &project.build_dir))?;
+            std::fs::write(&response_file, contents)
+                .map_err(|io| Error::file_io(io, &response_file))?;
+        }
+
+        let mut command = program.to_vec();
+        command.push(format!(
+            "{}{}",
+            self.response_file_option,
+            response_file.display()
+        ));
+        Ok(command)
+    }
+
+    fn archive_output_filename(&self, target: &Target) -> PathBuf {
+        target.path.join(
+            self.resolve_archive_output_format(&target.name)
+                .replace("%target", &target.name),
+        )
+    }
+
+    fn dynamic_output_filename(&self, target: &Target) -> PathBuf {
+        target.path.join(
+            self.resolve_linker_dynamic_output_format(&target.name)
+                .replace("%target", &target.name),
+        )
+    }
+
+    /// Where linking or archiving a target puts its output. Header-only targets have none.
+    pub fn output_filename(&self, target: &Target, target_type: &TargetType) -> Option<PathBuf> {
+        match target_type {
+            TargetType::Archive => Some(self.archive_output_filename(target)),
+            TargetType::Dynamic => Some(self.dynamic_output_filename(target)),
+            TargetType::Binary => Some(self.binary_output_filename(target)),
+            TargetType::HeaderOnly => None,
+        }
+    }
+
+    /// Environment variables for the compiler, archiver, and linker: the project's env, then the
+    /// target's, then any from --env, with %target substituted
+    fn command_env(
+        &self,
+        project: &Project,
+        target: &Target,
+        options: &BuildOptions,
+    ) -> Vec<(String, String)> {
+        project
+            .project
+            .env
+            .iter()
+            .chain(target.env.iter())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .chain(options.env.iter().cloned())
+            .map(|(key, value)| (key, value.replace("%target", &target.name)))
+            .collect()
+    }
+
+    /// The output that %output refers to in pre_build and post_build: the binary, the dynamic
+    /// library, or the archive, in that order
+    fn main_output_filename(&self, target: &Target) -> Option<PathBuf> {
+        [TargetType::Binary, TargetType::Dynamic, TargetType::Archive]
+            .into_iter()
+            .find(|target_type| target.type_.contains(target_type))
+            .and_then(|target_type| self.output_filename(target, &target_type))
+    }
+
+    /// A target's pre_build or post_build commands with %target and %output replaced
+    fn build_commands(&self, target: &Target, commands: &[String]) -> Vec<String> {
+        let output = self
+            .main_output_filename(target)
+            .map(|output| output.display().to_string())
+            .unwrap_or_default();
+
+        commands
+            .iter()
+            .map(|command| {
+                command
+                    .replace("%target", &target.name)
+                    .replace("%output", &output)
+            })
+            .collect()
+    }
+
+    /// Run a target's pre_build or post_build commands with the shell, in the target's directory
+    pub fn run_build_commands(
+        &self,
+        target: &Target,
+        commands: &[String],
+        options: &BuildOptions,
     ) -> Result<(), Error> {
-        tracing::info!("Archiving target {}", target.name);
+        for command in self.build_commands(target, commands) {
+            tracing::info!("Running {:?} for {}", command, target.name);
+            if options.dry_run {
+                tracing::debug!("Skipping due to --dry-run");
+                continue;
+            }
+
+            let exec = subprocess::Exec::shell(&command).cwd(&target.path);
+            if let Some(failure) = run_exec_captured(exec, &target.name)? {
+                return Err(Error::BuildCommandFailed {
+                    target: target.name.clone(),
+                    command,
+                    status: failure.status,
+                    output: failure.output,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_header_only(&self, project: &Project, need: &str) -> bool {
+        project
+            .target
+            .get(need)
+            .is_some_and(|need| need.type_.contains(&TargetType::HeaderOnly))
+    }
 
+    /// A needed target which is only an archive is linked by the path to the archive, since its
+    /// name from archive_output_format may not be what the linker option expects
+    fn needed_archive(&self, project: &Project, need: &str) -> Option<PathBuf> {
+        let need = project.target.get(need)?;
+        if need.type_.contains(&TargetType::Archive) && !need.type_.contains(&TargetType::Dynamic) {
+            Some(self.archive_output_filename(need))
+        } else {
+            None
+        }
+    }
+
+    pub fn binary_output_filename(&self, target: &Target) -> PathBuf {
+        let name = target.output_name.as_deref().unwrap_or(&target.name);
+        target
+            .path
+            .join(format!("{}{}", name, std::env::consts::EXE_SUFFIX))
+    }
+
+    /// Remove the object files and outputs of `targets`, returning how many were removed
+    pub fn clean<'t>(
+        &self,
+        project: &Project,
+        targets: impl IntoIterator<Item = &'t Target>,
+        dry_run: bool,
+    ) -> Result<usize, Error> {
+        let mut removed = 0;
+        for target in targets {
+            let mut paths = self.object_filenames(project, target)?;
+            paths.extend(
+                target
+                    .type_
+                    .iter()
+                    .filter_map(|target_type| self.output_filename(target, target_type)),
+            );
+            paths.extend(
+                ["archive", "link"].map(|step| self.response_filename(project, target, step)),
+            );
+
+            for path in paths {
+                if !path.exists() {
+                    continue;
+                }
+
+                tracing::debug!("Removing {}", path.display());
+                if !dry_run {
+                    std::fs::remove_file(&path).map_err(|io| Error::file_io(io, &path))?;
+                }
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Copy binaries to `prefix/bin`, libraries to `prefix/lib`, and headers to
+    /// `prefix/include`, returning how many files were copied
+    pub fn install<'t>(
+        &self,
+        targets: impl IntoIterator<Item = &'t Target>,
+        prefix: &Path,
+        dry_run: bool,
+    ) -> Result<usize, Error> {
+        let mut installed = 0;
+        for target in targets {
+            let mut files = Vec::new();
+            for target_type in target.type_.iter() {
+                let dir = match target_type {
+                    TargetType::Binary => "bin",
+                    TargetType::Archive | TargetType::Dynamic => "lib",
+                    TargetType::HeaderOnly => continue,
+                };
+                if let Some(output) = self.output_filename(target, target_type) {
+                    files.push((output, dir));
+                }
+            }
+            files.extend(
+                target
+                    .headers
+                    .iter()
+                    .map(|header| (header.clone(), "include")),
+            );
+
+            for (file, dir) in files {
+                if !file.exists() {
+                    return Err(Error::NotBuilt(file.display().to_string()));
+                }
+
+                let file_name = file
+                    .file_name()
+                    .ok_or_else(|| Error::NoFilename(file.display().to_string()))?;
+                let dir = prefix.join(dir);
+                let dest = dir.join(file_name);
+
+                tracing::info!("Installing {} to {}", file.display(), dest.display());
+                if !dry_run {
+                    std::fs::create_dir_all(&dir).map_err(|io| Error::file_io(io, &dir))?;
+                    std::fs::copy(&file, &dest).map_err(|io| Error::file_io(io, &dest))?;
+                }
+                installed += 1;
+            }
+        }
+
+        Ok(installed)
+    }
+
+    pub fn archive_command_line(
+        &self,
+        project: &Project,
+        target: &Target,
+        options: &BuildOptions,
+    ) -> Result<Vec<String>, Error> {
         let archive_command = self.resolve_archive_command(&target.name);
         let archive_format = self.resolve_archive_format(&target.name);
-        let archive_output_format = self.resolve_archive_output_format(&target.name);
         let archive_verbose_flag = self.resolve_archive_verbose_flag(&target.name);
         let archive_flag = self.resolve_archive_flag(&target.name);
 
-        //"%verbose_flag" if verbose => command.push(archive_verbose_flag.clone()), //"%verbose_flag" if !verbose => {}
-        let mut replace_objects = String::new();
-        for source_path in target.sources.iter() {
-            let short_source_path = self.short_source_path(project, source_path)?;
-            replace_objects.push_str(
-                &self
-                    .compile_output_filename(&short_source_path, source_path)?
-                    .display()
-                    .to_string(),
-            );
-            replace_objects.push(' ');
+        let output = self.archive_output_filename(target).display().to_string();
+        let objects = self
+            .object_filenames(project, target)?
+            .into_iter()
+            .map(|object| object.display().to_string())
+            .collect::<Vec<_>>();
+
+        let scalars = [
+            ("%command", archive_command),
+            (
+                "%verbose_flag",
+                flag_if(options.verbose, archive_verbose_flag),
+            ),
+            ("%archive_flag", archive_flag),
+            ("%output", output),
+        ];
+        let lists = [("%objects", objects.clone())];
+
+        // flags may be glued together or onto the output, e.g. "%verbose_flag%archive_flag"
+        let mut command = Vec::<String>::new();
+        for part in archive_format.split(" ") {
+            match part {
+                "%objects" => command.extend(objects.iter().cloned()),
+                _ if part.contains("%") => command.extend(substitute(part, &scalars, &lists)?),
+                _ => command.push(part.into()),
+            }
         }
 
-        let command = archive_format
-            .replace("%command", &archive_command)
-            .replace("%objects", &replace_objects)
-            .replace("%archive_flag", &archive_flag)
-            .replace(
-                "%output",
-                &target
-                    .path
-                    .join(&archive_output_format.replace("%target", &target.name))
-                    .display()
-                    .to_string(),
-            );
+        command.retain(|part| !part.is_empty());
+        Ok(command)
+    }
 
-        let command = if verbose {
-            command.replace("%verbose_flag", &archive_verbose_flag)
-        } else {
-            command.replace("%verbose_flag", "")
-        };
+    pub fn create_archive(
+        &self,
+        project: &Project,
+        target: &Target,
+        options: &BuildOptions,
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        tracing::info!("{} Archiving target {}", progress.next(), target.name);
 
-        let command_vec = command.split_whitespace().collect::<Vec<_>>();
+        let command = self.archive_command_line(project, target, options)?;
+        let command_vec =
+            self.use_response_file(project, target, "archive", command, 1, options.dry_run)?;
 
         tracing::info!("{:?}", command_vec);
-        if dry_run {
+        if options.dry_run {
             tracing::debug!("Skipping due to --dry-run");
             return Ok(());
         }
-        let status = subprocess::Exec::cmd(&command_vec[0])
-            .args(&command_vec[..])
-            .join()?;
-        if !status.success() {
-            Err(Error::ArchiveFailed)
-        } else {
-            Ok(())
+        let env = self.command_env(project, target, options);
+        match run_captured(&command_vec, &target.name, &env)? {
+            Some(failure) => Err(Error::ArchiveFailed {
+                target: target.name.clone(),
+                command: shell_command(&command_vec),
+                status: failure.status,
+                output: failure.output,
+            }),
+            None => Ok(()),
         }
     }
 
-    pub fn link_dynamic(
+    pub fn dynamic_link_command_line(
         &self,
         project: &Project,
         target: &Target,
-        verbose: bool,
-        debug: bool,
-        dry_run: bool,
-    ) -> Result<(), Error> {
-        tracing::info!("Linking dynamic target {}", target.name);
-
-        let mut link_paths = vec![target.path.as_path()];
-        for need in target.needs.iter() {
-            link_paths.push(
-                project
-                    .target
-                    .get(need.as_str())
-                    .ok_or_else(|| Error::Bug(format!("Resolved project had unknown target")))?
-                    .path
-                    .as_path(),
-            );
-        }
-        let link_paths = self.resolve_linker_paths(&target.name, &link_paths);
+        profile: &Profile,
+        options: &BuildOptions,
+    ) -> Result<Vec<String>, Error> {
+        let link_paths = self.link_paths(project, target)?;
+        let link_paths = self
+            .resolve_linker_paths(&target.name, &link_paths)
+            .split(PATH_SEPARATOR)
+            .filter(|path| !path.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>();
 
         let linker_command = self.resolve_link_command(&target.name);
+        let linker_wrapper = self.resolve_linker_wrapper(&target.name);
         let linker_verbose_flag = self.resolve_linker_verbose_flag(&target.name);
         let linker_debug_flag = self.resolve_linker_debug_flag(&target.name);
+        let linker_sanitize_option = self.resolve_linker_sanitize_option(&target.name);
         let linker_dynamic_link_flag = self.resolve_linker_dynamic_link_flag(&target.name);
         let linker_output_option = self.resolve_linker_output_option(&target.name);
-        let linker_dynamic_output_format = self.resolve_linker_dynamic_output_format(&target.name);
         let link_path_option = self.resolve_linker_link_path_option(&target.name);
         let command_format = self.resolve_dynamic_link_command_format(&target.name);
 
+        let objects = self
+            .object_filenames(project, target)?
+            .into_iter()
+            .map(|object| object.display().to_string())
+            .collect::<Vec<_>>();
+
+        let scalars = [
+            ("%command", linker_command.clone()),
+            (
+                "%verbose_flag",
+                flag_if(options.verbose, linker_verbose_flag),
+            ),
+            ("%debug_flag", flag_if(profile.debug, linker_debug_flag)),
+            ("%dynamic_link_flag", linker_dynamic_link_flag),
+            (
+                "%sanitize",
+                sanitize_flag(&linker_sanitize_option, &options.sanitize),
+            ),
+            ("%output_option", linker_output_option),
+            (
+                "%output",
+                self.dynamic_output_filename(target).display().to_string(),
+            ),
+        ];
+        let lists = [
+            ("%objects", objects.clone()),
+            ("%link_paths", link_paths.clone()),
+        ];
+
         let mut command = Vec::<String>::new();
         for part in command_format.split(" ") {
             match part {
-                "%command" => command.push(linker_command.clone()),
-                "%verbose_flag" if verbose => command.push(linker_verbose_flag.clone()),
-                "%verbose_flag" if !verbose => {}
-                "%debug_flag" if debug => command.push(linker_debug_flag.clone()),
-                "%debug_flag" if !debug => {}
-                "%dynamic_link_flag" => command.push(linker_dynamic_link_flag.clone()),
-                "%objects" => {
-                    for source_path in target.sources.iter() {
-                        let short_source_path = self.short_source_path(project, source_path)?;
-                        command.push(
-                            self.compile_output_filename(&short_source_path, source_path)?
-                                .display()
-                                .to_string(),
-                        );
-                    }
+                "%command" => {
+                    command.extend(linker_wrapper.split_whitespace().map(String::from));
+                    command.push(linker_command.clone());
                 }
+                "%objects" => command.extend(objects.iter().cloned()),
                 "%link_paths" => {
-                    for path in link_paths.split(PATH_SEPARATOR) {
-                        if path != "" {
-                            command.push(link_path_option.clone());
-                            command.push(path.into());
-                        }
+                    for path in link_paths.iter() {
+                        command.push(format!("{}{}", link_path_option, path));
                     }
                 }
                 "%links" => {
                     for need in target.needs.iter() {
-                        command.push(self.resolve_linker_link_option(need));
-                        command.push(need.clone());
+                        if self.is_header_only(project, need) {
+                            continue;
+                        }
+                        if let Some(archive) = self.needed_archive(project, need) {
+                            command.push(archive.display().to_string());
+                            continue;
+                        }
+                        command.push(self.link_library(need));
                     }
+                    command.extend(self.pkg_config_libs(target)?);
+                    command.extend(self.frameworks(target));
                 }
-                "%output_option" => command.push(linker_output_option.clone()),
-                "%output" => {
-                    command.push(
-                        target
-                            .path
-                            .join(linker_dynamic_output_format.replace("%target", &target.name))
-                            .display()
-                            .to_string(),
-                    );
-                }
-                _ if part.starts_with("%") => return Err(Error::UnknownSubstitution(part.into())),
+                _ if part.contains("%") => command.extend(substitute(part, &scalars, &lists)?),
                 _ => command.push(part.into()),
             }
         }
 
+        command.retain(|part| !part.is_empty());
+        Ok(command)
+    }
+
+    pub fn link_dynamic(
+        &self,
+        project: &Project,
+        target: &Target,
+        profile: &Profile,
+        options: &BuildOptions,
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        tracing::info!("{} Linking dynamic target {}", progress.next(), target.name);
+
+        let command = self.dynamic_link_command_line(project, target, profile, options)?;
+        let command = self.use_response_file(
+            project,
+            target,
+            "link",
+            command,
+            self.linker_program_len(&target.name),
+            options.dry_run,
+        )?;
+
         tracing::info!("{:?}", command);
-        if dry_run {
+        if options.dry_run {
             tracing::debug!("Skipping due to --dry-run");
             return Ok(());
         }
-        let status = subprocess::Exec::cmd(&command[0])
-            .args(&command[1..])
-            .join()?;
-        if !status.success() {
-            Err(Error::LinkFailed)
-        } else {
-            Ok(())
+        let env = self.command_env(project, target, options);
+        match run_captured(&command, &target.name, &env)? {
+            Some(failure) => Err(Error::LinkFailed {
+                target: target.name.clone(),
+                command: shell_command(&command),
+                status: failure.status,
+                output: failure.output,
+            }),
+            None => Ok(()),
         }
     }
 
-    pub fn link_binary(
+    pub fn binary_link_command_line(
         &self,
         project: &Project,
         target: &Target,
-        verbose: bool,
-        debug: bool,
-        dry_run: bool,
-    ) -> Result<(), Error> {
-        tracing::info!("Linking binary target {}", target.name);
-
-        let mut link_paths = vec![target.path.as_path()];
-        for need in target.needs.iter() {
-            link_paths.push(
-                project
-                    .target
-                    .get(need.as_str())
-                    .ok_or_else(|| Error::Bug(format!("Resolved project had unknown target")))?
-                    .path
-                    .as_path(),
-            );
-        }
-        let link_paths = self.resolve_linker_paths(&target.name, &link_paths);
+        profile: &Profile,
+        options: &BuildOptions,
+    ) -> Result<Vec<String>, Error> {
+        let link_paths = self.link_paths(project, target)?;
+        let link_paths = self
+            .resolve_linker_paths(&target.name, &link_paths)
+            .split(PATH_SEPARATOR)
+            .filter(|path| !path.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>();
 
         let linker_command = self.resolve_link_command(&target.name);
+        let linker_wrapper = self.resolve_linker_wrapper(&target.name);
         let linker_verbose_flag = self.resolve_linker_verbose_flag(&target.name);
         let linker_debug_flag = self.resolve_linker_debug_flag(&target.name);
+        let linker_sanitize_option = self.resolve_linker_sanitize_option(&target.name);
         let linker_output_option = self.resolve_linker_output_option(&target.name);
         let link_path_option = self.resolve_linker_link_path_option(&target.name);
         let command_format = self.resolve_binary_link_command_format(&target.name);
 
-        let mut replace_objects = String::new();
-        for source_path in target.sources.iter() {
-            let short_source_path = self.short_source_path(project, source_path)?;
-            replace_objects.push_str(
-                &self
-                    .compile_output_filename(&short_source_path, source_path)?
-                    .display()
-                    .to_string(),
-            );
-            replace_objects.push(' ');
-        }
+        let objects = self
+            .object_filenames(project, target)?
+            .into_iter()
+            .map(|object| object.display().to_string())
+            .collect::<Vec<_>>();
 
-        let mut replace_link_paths = String::new();
-        for path in link_paths.split(PATH_SEPARATOR) {
-            if path != "" {
-                replace_link_paths.push_str(&link_path_option);
-                replace_link_paths.push_str(&path);
-                replace_link_paths.push(' ');
+        let scalars = [
+            ("%command", linker_command.clone()),
+            (
+                "%verbose_flag",
+                flag_if(options.verbose, linker_verbose_flag),
+            ),
+            ("%debug_flag", flag_if(profile.debug, linker_debug_flag)),
+            (
+                "%sanitize",
+                sanitize_flag(&linker_sanitize_option, &options.sanitize),
+            ),
+            ("%output_option", linker_output_option),
+            (
+                "%output",
+                self.binary_output_filename(target).display().to_string(),
+            ),
+        ];
+        let lists = [
+            ("%objects", objects.clone()),
+            ("%link_paths", link_paths.clone()),
+        ];
+
+        let mut command = Vec::<String>::new();
+        for part in command_format.split(" ") {
+            match part {
+                "%command" => {
+                    command.extend(linker_wrapper.split_whitespace().map(String::from));
+                    command.push(linker_command.clone());
+                }
+                "%objects" => command.extend(objects.iter().cloned()),
+                "%link_paths" => {
+                    for path in link_paths.iter() {
+                        command.push(format!("{}{}", link_path_option, path));
+                    }
+                }
+                "%links" => {
+                    for need in target.needs.iter() {
+                        if self.is_header_only(project, need) {
+                            continue;
+                        }
+                        if let Some(archive) = self.needed_archive(project, need) {
+                            command.push(archive.display().to_string());
+                            continue;
+                        }
+                        command.push(self.link_library(need));
+                    }
+                    command.extend(self.pkg_config_libs(target)?);
+                    command.extend(self.frameworks(target));
+                    command.extend(self.rpaths(project, target));
+                }
+                _ if part.contains("%") => command.extend(substitute(part, &scalars, &lists)?),
+                _ => command.push(part.into()),
             }
         }
 
-        let mut replace_links = String::new();
-        for need in target.needs.iter() {
-            replace_links.push_str(&self.resolve_linker_link_option(need));
-            replace_links.push_str(&need);
-            replace_links.push(' ');
-        }
-
-        let command = command_format
-            .replace("%command", &linker_command)
-            .replace("%objects", &replace_objects)
-            .replace("%link_paths", &replace_link_paths)
-            .replace("%links", &replace_links)
-            .replace("%output_option", &linker_output_option)
-            .replace(
-                "%output",
-                &target.path.join(&target.name).display().to_string(),
-            );
+        command.retain(|part| !part.is_empty());
+        Ok(command)
+    }
 
-        let command = if verbose {
-            command.replace("%verbose_flag", &linker_verbose_flag)
-        } else {
-            command.replace("%verbose_flag", "")
-        };
-        let command = if debug {
-            command.replace("%debug_flag", &linker_debug_flag)
-        } else {
-            command.replace("%debug_flag", "")
-        };
+    pub fn link_binary(
+        &self,
+        project: &Project,
+        target: &Target,
+        profile: &Profile,
+        options: &BuildOptions,
+        progress: &Progress,
+    ) -> Result<(), Error> {
+        tracing::info!("{} Linking binary target {}", progress.next(), target.name);
+
+        let command = self.binary_link_command_line(project, target, profile, options)?;
+        let command_vec = self.use_response_file(
+            project,
+            target,
+            "link",
+            command,
+            self.linker_program_len(&target.name),
+            options.dry_run,
+        )?;
 
-        let command_vec = command.split_whitespace().collect::<Vec<_>>();
         tracing::info!("{:?}", command_vec);
-        if dry_run {
+        if options.dry_run {
             tracing::debug!("Skipping due to --dry-run");
             return Ok(());
         }
-        let status = subprocess::Exec::cmd(&command_vec[0])
-            .args(&command_vec[1..])
-            .join()?;
-        if !status.success() {
-            Err(Error::LinkFailed)
-        } else {
-            Ok(())
+        let env = self.command_env(project, target, options);
+        match run_captured(&command_vec, &target.name, &env)? {
+            Some(failure) => Err(Error::LinkFailed {
+                target: target.name.clone(),
+                command: shell_command(&command_vec),
+                status: failure.status,
+                output: failure.output,
+            }),
+            None => Ok(()),
         }
     }
 }
+
+fn flag_if(enabled: bool, flag: String) -> String {
+    if enabled {
+        flag
+    } else {
+        String::new()
+    }
+}
+
+fn option_with(option: &str, value: Option<&str>) -> String {
+    value
+        .map(|value| format!("{}{}", option, value))
+        .unwrap_or_default()
+}
+
+fn sanitize_flag(option: &str, sanitizers: &[String]) -> String {
+    if sanitizers.is_empty() {
+        String::new()
+    } else {
+        format!("{}{}", option, sanitizers.join(","))
+    }
+}
+
+/// Substitute the tokens in one part of a command format. Tokens may be glued to each other
+/// or to plain text, like "%output_option%output" or "/I%includes", to make a single argument.
+/// A part with a list token becomes one argument per item in the list.
+fn substitute(
+    part: &str,
+    scalars: &[(&str, String)],
+    lists: &[(&str, Vec<String>)],
+) -> Result<Vec<String>, Error> {
+    enum Piece<'a> {
+        Text(&'a str),
+        Scalar(&'a str),
+        List,
+    }
+
+    let mut pieces = Vec::new();
+    let mut list = None;
+    let mut rest = part;
+    while !rest.is_empty() {
+        let Some(start) = rest.find('%') else {
+            pieces.push(Piece::Text(rest));
+            break;
+        };
+        pieces.push(Piece::Text(&rest[..start]));
+        rest = &rest[start..];
+
+        // the longest name wins so %output_option isn't read as %output
+        let scalar = scalars
+            .iter()
+            .filter(|(name, _)| rest.starts_with(name))
+            .max_by_key(|(name, _)| name.len());
+        let list_token = lists
+            .iter()
+            .filter(|(name, _)| rest.starts_with(name))
+            .max_by_key(|(name, _)| name.len());
+
+        match (scalar, list_token) {
+            (Some((name, value)), other)
+                if other.is_none_or(|(other, _)| other.len() < name.len()) =>
+            {
+                pieces.push(Piece::Scalar(value));
+                rest = &rest[name.len()..];
+            }
+            (_, Some((name, values))) if list.is_none() => {
+                list = Some(values);
+                pieces.push(Piece::List);
+                rest = &rest[name.len()..];
+            }
+            _ => return Err(Error::UnknownSubstitution(part.into())),
+        }
+    }
+
+    let join = |item: &str| {
+        pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) | Piece::Scalar(text) => *text,
+                Piece::List => item,
+            })
+            .collect::<String>()
+    };
+
+    Ok(match list {
+        Some(values) => values.iter().map(|item| join(item)).collect(),
+        None => vec![join("")],
+    })
+}
+
+fn quote_response_file_arg(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.into()
+    }
+}
+
+const FAILURE_OUTPUT_LINES: usize = 20;
+
+/// How a command failed
+struct CommandFailure {
+    /// How it exited, like "exited with code 1"
+    status: String,
+    /// The last FAILURE_OUTPUT_LINES lines it printed
+    output: String,
+}
+
+/// Run a command with its stdout and stderr captured together. Output from a successful
+/// command is logged since it may hold warnings. If the command fails, how it exited and the
+/// last few lines of its output are returned instead.
+fn run_captured(
+    command: &[String],
+    name: &str,
+    env: &[(String, String)],
+) -> Result<Option<CommandFailure>, Error> {
+    let mut exec = subprocess::Exec::cmd(&command[0]).args(&command[1..]);
+    for (key, value) in env {
+        exec = exec.env(key, value);
+    }
+    run_exec_captured(exec, name)
+}
+
+fn run_exec_captured(exec: subprocess::Exec, name: &str) -> Result<Option<CommandFailure>, Error> {
+    let capture = exec
+        .stdout(subprocess::Redirection::Pipe)
+        .stderr(subprocess::Redirection::Merge)
+        .capture()?;
+
+    let output = capture.stdout_str();
+    let output = output.trim_end();
+    if capture.success() {
+        if !output.is_empty() {
+            tracing::info!("Output from {}:\n{}", name, output);
+        }
+        return Ok(None);
+    }
+
+    let lines = output.lines().collect::<Vec<_>>();
+    let tail = &lines[lines.len().saturating_sub(FAILURE_OUTPUT_LINES)..];
+    let status = match capture.exit_status {
+        subprocess::ExitStatus::Exited(code) => format!("exited with code {}", code),
+        subprocess::ExitStatus::Signaled(signal) => format!("was killed by signal {}", signal),
+        subprocess::ExitStatus::Other(status) => format!("exited with status {}", status),
+        subprocess::ExitStatus::Undetermined => String::from("exited with an unknown status"),
+    };
+    Ok(Some(CommandFailure {
+        status,
+        output: tail.join("\n"),
+    }))
+}
@@ -0,0 +1,62 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// Find the headers a source file includes, following includes recursively. A
+/// `#include "..."` is searched for in the including file's directory and then in
+/// `include_paths`, and a `#include <...>` only in `include_paths`. Headers which can't be
+/// found (system headers, generated files) are ignored, and each header is only visited once
+/// so include cycles terminate.
+pub fn included_headers<S: AsRef<Path>>(source_path: &Path, include_paths: &[S]) -> Vec<PathBuf> {
+    let mut headers = Vec::new();
+    let mut seen = HashSet::new();
+    let mut to_scan = vec![source_path.to_owned()];
+
+    while let Some(file) = to_scan.pop() {
+        let Ok(contents) = std::fs::read_to_string(&file) else {
+            tracing::trace!("Could not scan {} for includes", file.display());
+            continue;
+        };
+
+        for (name, quoted) in contents.lines().filter_map(include_name) {
+            let Some(header) = file
+                .parent()
+                .filter(|_| quoted)
+                .into_iter()
+                .chain(include_paths.iter().map(|path| path.as_ref()))
+                .map(|dir| dir.join(name))
+                .find(|header| header.is_file())
+            else {
+                tracing::trace!("Could not find {} included by {}", name, file.display());
+                continue;
+            };
+
+            let header = header.canonicalize().unwrap_or(header);
+            if seen.insert(header.clone()) {
+                tracing::trace!("{} includes {}", file.display(), header.display());
+                headers.push(header.clone());
+                to_scan.push(header);
+            }
+        }
+    }
+
+    headers
+}
+
+/// The name in an include directive, and whether it was quoted rather than in angle brackets
+fn include_name(line: &str) -> Option<(&str, bool)> {
+    let rest = line
+        .trim_start()
+        .strip_prefix('#')?
+        .trim_start()
+        .strip_prefix("include")?
+        .trim_start();
+
+    if let Some(rest) = rest.strip_prefix('"') {
+        rest.split_once('"').map(|(name, _)| (name, true))
+    } else {
+        let rest = rest.strip_prefix('<')?;
+        rest.split_once('>').map(|(name, _)| (name, false))
+    }
+}
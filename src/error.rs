@@ -12,10 +12,19 @@ pub enum Error {
     FileIo { io: std::io::Error, path: String },
 
     #[error("Could not read project file {path}: {toml}")]
-    ReadProject { toml: toml::de::Error, path: String },
+    ReadProject {
+        toml: Box<toml::de::Error>,
+        path: String,
+    },
 
     #[error("Could not read TOML file {path}: {toml}")]
-    GenericToml { toml: toml::de::Error, path: String },
+    GenericToml {
+        toml: Box<toml::de::Error>,
+        path: String,
+    },
+
+    #[error("File already exists: {0}")]
+    AlreadyExists(String),
 
     #[error("No project file in this directory or any parent")]
     NoProject,
@@ -26,17 +35,39 @@ pub enum Error {
     #[error("No config directory")]
     NoConfigDir,
 
+    #[error("No compilers file at {0}")]
+    NoCompilersFile(String),
+
     #[error("Substitution was not valid: {0}")]
     UnknownSubstitution(String),
 
     #[error("File does not have a name: {0}")]
     NoFilename(String),
 
-    #[error("No compiler named {name}")]
-    NoCompiler { name: String },
+    #[error("Invalid glob pattern {pattern}: {why}")]
+    BadGlob { pattern: String, why: String },
+
+    #[error("Glob pattern did not match any files: {0}")]
+    EmptyGlob(String),
+
+    #[error("Path is outside the project directory (see allow_external_paths): {0}")]
+    ExternalPath(String),
+
+    #[error("Unknown keys for compiler {compiler} in {path}: {}", keys.join(", "))]
+    UnknownCompilerKeys {
+        compiler: String,
+        keys: Vec<String>,
+        path: String,
+    },
 
-    #[error("Many compilers named {name}")]
-    ManyCompilers { name: String },
+    #[error("No compiler named '{name}'; available: {}", available.join(", "))]
+    NoCompiler {
+        name: String,
+        available: Vec<String>,
+    },
+
+    #[error("pkg-config failed for {package}: {why}")]
+    PkgConfig { package: String, why: String },
 
     #[error("Compiler is broken: {why}")]
     CompilerBroken { why: String },
@@ -44,23 +75,238 @@ pub enum Error {
     #[error("Could not run compiler: {0}")]
     CouldNotRunCompiler(#[from] subprocess::PopenError),
 
-    #[error("Compilation failed")]
-    CompilationFailed,
+    #[error("Compiling {source_file} failed, {command} {status}:\n{output}")]
+    CompilationFailed {
+        source_file: String,
+        command: String,
+        status: String,
+        output: String,
+    },
+
+    #[error("Linking {target} failed, {command} {status}:\n{output}")]
+    LinkFailed {
+        target: String,
+        command: String,
+        status: String,
+        output: String,
+    },
+
+    #[error("Archiving {target} failed, {command} {status}:\n{output}")]
+    ArchiveFailed {
+        target: String,
+        command: String,
+        status: String,
+        output: String,
+    },
+
+    #[error("Command {command:?} for {target} {status}:\n{output}")]
+    BuildCommandFailed {
+        target: String,
+        command: String,
+        status: String,
+        output: String,
+    },
+
+    #[error("Targets failed: {}", .0.join(", "))]
+    TargetsFailed(Vec<String>),
+
+    #[error("Invalid project:{}", .0.iter().map(|err| format!("\n  {}", err)).collect::<String>())]
+    InvalidProject(Vec<Error>),
+
+    #[error("Invalid target {name}: {why}")]
+    InvalidTarget { name: String, why: String },
+
+    #[error(
+        "Invalid project version {version:?}, expected MAJOR.MINOR.PATCH like \"1.0.0\": {why}"
+    )]
+    InvalidVersion { version: String, why: String },
+
+    #[error("Could not expand {value:?}: {why}")]
+    EnvExpansion { value: String, why: String },
+
+    #[error("Target {0} has no sources")]
+    NoSources(String),
+
+    #[error("Path of target {target} does not exist: {path}")]
+    TargetPathNotFound { target: String, path: String },
+
+    #[error("Source of target {target} does not exist: {path}")]
+    SourceNotFound { target: String, path: String },
 
-    #[error("Linking failed")]
-    LinkFailed,
+    #[error("Header of target {target} does not exist: {path}")]
+    HeaderNotFound { target: String, path: String },
 
-    #[error("Archiving failed")]
-    ArchiveFailed,
+    #[error("Include directory of target {target} does not exist: {path}")]
+    IncludeDirNotFound { target: String, path: String },
+
+    #[error("Target {name} in {path} is already defined")]
+    DuplicateTarget { name: String, path: String },
+
+    #[error("{first} and {second} would compile to the same object file")]
+    SameObject { first: String, second: String },
 
     #[error("No such build target: {0}")]
     NoSuchBuildTarget(String),
 
+    #[error("{0} does not exist, build the project first")]
+    NotBuilt(String),
+
+    #[error("Target {0} is not a binary")]
+    NotBinary(String),
+
+    #[error("No such profile: {0}")]
+    NoSuchProfile(String),
+
+    #[error("Dependency cycle: {}", path.join(" needs "))]
+    DependencyCycle { path: Vec<String> },
+
+    #[error("Could not watch files: {0}")]
+    Watch(#[from] notify::Error),
+
     #[error("Bug. Please report this! {0}")]
     Bug(String),
 }
 
+/// Broad kinds of errors, for telling failures apart without matching on messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The command line asked for something that can't be done
+    Usage,
+    /// There's no project file to use
+    NoProject,
+    /// Reading or writing files failed, or something unexpected happened
+    Io,
+    /// The project file couldn't be read as written
+    Parse,
+    /// The project file was read, but its targets don't make sense
+    Resolve,
+    /// A compiler, archiver, linker, or build command failed
+    Compile,
+    /// compilers.toml or the compiler it describes can't be used
+    Config,
+}
+
 impl Error {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::Cli(_) | Error::AlreadyExists(_) | Error::NotBuilt(_) | Error::NotBinary(_) => {
+                ErrorCategory::Usage
+            }
+
+            Error::NoProject | Error::NoProjectDir => ErrorCategory::NoProject,
+
+            Error::GenericIo(_) | Error::FileIo { .. } | Error::Watch(_) | Error::Bug(_) => {
+                ErrorCategory::Io
+            }
+
+            Error::ReadProject { .. }
+            | Error::BadGlob { .. }
+            | Error::InvalidVersion { .. }
+            | Error::EnvExpansion { .. } => ErrorCategory::Parse,
+
+            Error::NoFilename(_)
+            | Error::EmptyGlob(_)
+            | Error::ExternalPath(_)
+            | Error::InvalidProject(_)
+            | Error::InvalidTarget { .. }
+            | Error::NoSources(_)
+            | Error::TargetPathNotFound { .. }
+            | Error::SourceNotFound { .. }
+            | Error::HeaderNotFound { .. }
+            | Error::IncludeDirNotFound { .. }
+            | Error::DuplicateTarget { .. }
+            | Error::SameObject { .. }
+            | Error::NoSuchBuildTarget(_)
+            | Error::NoSuchProfile(_)
+            | Error::DependencyCycle { .. } => ErrorCategory::Resolve,
+
+            Error::CompilationFailed { .. }
+            | Error::LinkFailed { .. }
+            | Error::ArchiveFailed { .. }
+            | Error::BuildCommandFailed { .. }
+            | Error::TargetsFailed(_) => ErrorCategory::Compile,
+
+            Error::GenericToml { .. }
+            | Error::NoConfigDir
+            | Error::NoCompilersFile(_)
+            | Error::UnknownCompilerKeys { .. }
+            | Error::UnknownSubstitution(_)
+            | Error::NoCompiler { .. }
+            | Error::PkgConfig { .. }
+            | Error::CompilerBroken { .. }
+            | Error::CouldNotRunCompiler(_) => ErrorCategory::Config,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Cli(_) => "Cli",
+            Error::GenericIo(_) => "GenericIo",
+            Error::FileIo { .. } => "FileIo",
+            Error::ReadProject { .. } => "ReadProject",
+            Error::GenericToml { .. } => "GenericToml",
+            Error::AlreadyExists(_) => "AlreadyExists",
+            Error::NoProject => "NoProject",
+            Error::NoProjectDir => "NoProjectDir",
+            Error::NoConfigDir => "NoConfigDir",
+            Error::NoCompilersFile(_) => "NoCompilersFile",
+            Error::UnknownSubstitution(_) => "UnknownSubstitution",
+            Error::NoFilename(_) => "NoFilename",
+            Error::BadGlob { .. } => "BadGlob",
+            Error::EmptyGlob(_) => "EmptyGlob",
+            Error::ExternalPath(_) => "ExternalPath",
+            Error::UnknownCompilerKeys { .. } => "UnknownCompilerKeys",
+            Error::NoCompiler { .. } => "NoCompiler",
+            Error::PkgConfig { .. } => "PkgConfig",
+            Error::CompilerBroken { .. } => "CompilerBroken",
+            Error::CouldNotRunCompiler(_) => "CouldNotRunCompiler",
+            Error::CompilationFailed { .. } => "CompilationFailed",
+            Error::LinkFailed { .. } => "LinkFailed",
+            Error::ArchiveFailed { .. } => "ArchiveFailed",
+            Error::BuildCommandFailed { .. } => "BuildCommandFailed",
+            Error::TargetsFailed(_) => "TargetsFailed",
+            Error::InvalidProject(_) => "InvalidProject",
+            Error::InvalidTarget { .. } => "InvalidTarget",
+            Error::InvalidVersion { .. } => "InvalidVersion",
+            Error::EnvExpansion { .. } => "EnvExpansion",
+            Error::NoSources(_) => "NoSources",
+            Error::TargetPathNotFound { .. } => "TargetPathNotFound",
+            Error::SourceNotFound { .. } => "SourceNotFound",
+            Error::HeaderNotFound { .. } => "HeaderNotFound",
+            Error::IncludeDirNotFound { .. } => "IncludeDirNotFound",
+            Error::DuplicateTarget { .. } => "DuplicateTarget",
+            Error::SameObject { .. } => "SameObject",
+            Error::NoSuchBuildTarget(_) => "NoSuchBuildTarget",
+            Error::NotBuilt(_) => "NotBuilt",
+            Error::NotBinary(_) => "NotBinary",
+            Error::NoSuchProfile(_) => "NoSuchProfile",
+            Error::DependencyCycle { .. } => "DependencyCycle",
+            Error::Watch(_) => "Watch",
+            Error::Bug(_) => "Bug",
+        }
+    }
+
+    /// The process exit code for this error, so scripts can tell failures apart. It follows
+    /// from the error's category:
+    ///
+    /// - 1: I/O errors and anything unexpected
+    /// - 2: invalid command line, or nothing to do what it asked with
+    /// - 3: no project file found
+    /// - 4: invalid project file
+    /// - 5: invalid or unusable compiler configuration
+    /// - 6: compiling, archiving, or linking failed
+    pub fn exit_code(&self) -> i32 {
+        match self.category() {
+            ErrorCategory::Io => 1,
+            ErrorCategory::Usage => 2,
+            ErrorCategory::NoProject => 3,
+            ErrorCategory::Parse | ErrorCategory::Resolve => 4,
+            ErrorCategory::Config => 5,
+            ErrorCategory::Compile => 6,
+        }
+    }
+
     pub fn file_io<P: AsRef<Path>>(io: std::io::Error, path: P) -> Error {
         Error::FileIo {
             io,
@@ -68,3 +314,240 @@ impl Error {
         }
     }
 }
+
+/// Errors are equal when they're the same variant with equal fields. The I/O, subprocess, and
+/// notify errors some variants wrap can't be compared directly, so their kinds or messages are
+/// compared instead.
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        if std::mem::discriminant(self) != std::mem::discriminant(other) {
+            return false;
+        }
+
+        // matching on self alone, so a new variant has to decide how it's compared
+        match self {
+            Error::NoProject | Error::NoProjectDir | Error::NoConfigDir => true,
+
+            Error::Cli(a)
+            | Error::AlreadyExists(a)
+            | Error::NoCompilersFile(a)
+            | Error::UnknownSubstitution(a)
+            | Error::NoFilename(a)
+            | Error::EmptyGlob(a)
+            | Error::ExternalPath(a)
+            | Error::NoSources(a)
+            | Error::NoSuchBuildTarget(a)
+            | Error::NotBuilt(a)
+            | Error::NotBinary(a)
+            | Error::NoSuchProfile(a)
+            | Error::Bug(a) => matches!(
+                other,
+                Error::Cli(b)
+                | Error::AlreadyExists(b)
+                | Error::NoCompilersFile(b)
+                | Error::UnknownSubstitution(b)
+                | Error::NoFilename(b)
+                | Error::EmptyGlob(b)
+                | Error::ExternalPath(b)
+                | Error::NoSources(b)
+                | Error::NoSuchBuildTarget(b)
+                | Error::NotBuilt(b)
+                | Error::NotBinary(b)
+                | Error::NoSuchProfile(b)
+                | Error::Bug(b)
+                if a == b
+            ),
+
+            Error::GenericIo(a) => matches!(
+                other,
+                Error::GenericIo(b) if a.kind() == b.kind() && a.to_string() == b.to_string()
+            ),
+            Error::FileIo { io: a, path } => matches!(
+                other,
+                Error::FileIo { io: b, path: other_path }
+                if a.kind() == b.kind() && path == other_path
+            ),
+            Error::ReadProject { toml, path } | Error::GenericToml { toml, path } => matches!(
+                other,
+                Error::ReadProject { toml: other_toml, path: other_path }
+                | Error::GenericToml { toml: other_toml, path: other_path }
+                if toml == other_toml && path == other_path
+            ),
+            Error::CouldNotRunCompiler(a) => matches!(
+                other,
+                Error::CouldNotRunCompiler(b) if a.to_string() == b.to_string()
+            ),
+            Error::Watch(a) => matches!(
+                other,
+                Error::Watch(b) if a.paths == b.paths && a.to_string() == b.to_string()
+            ),
+
+            Error::UnknownCompilerKeys {
+                compiler,
+                keys,
+                path,
+            } => matches!(
+                other,
+                Error::UnknownCompilerKeys { compiler: other_compiler, keys: other_keys, path: other_path }
+                if compiler == other_compiler && keys == other_keys && path == other_path
+            ),
+            Error::NoCompiler { name, available } => matches!(
+                other,
+                Error::NoCompiler { name: other_name, available: other_available }
+                if name == other_name && available == other_available
+            ),
+            Error::CompilerBroken { why } => matches!(
+                other,
+                Error::CompilerBroken { why: other_why } if why == other_why
+            ),
+
+            Error::CompilationFailed {
+                source_file: name,
+                command,
+                status,
+                output,
+            }
+            | Error::LinkFailed {
+                target: name,
+                command,
+                status,
+                output,
+            }
+            | Error::ArchiveFailed {
+                target: name,
+                command,
+                status,
+                output,
+            }
+            | Error::BuildCommandFailed {
+                target: name,
+                command,
+                status,
+                output,
+            } => matches!(
+                other,
+                Error::CompilationFailed { source_file: other_name, command: other_command, status: other_status, output: other_output }
+                | Error::LinkFailed { target: other_name, command: other_command, status: other_status, output: other_output }
+                | Error::ArchiveFailed { target: other_name, command: other_command, status: other_status, output: other_output }
+                | Error::BuildCommandFailed { target: other_name, command: other_command, status: other_status, output: other_output }
+                if name == other_name && command == other_command && status == other_status && output == other_output
+            ),
+
+            Error::TargetsFailed(a) | Error::DependencyCycle { path: a } => matches!(
+                other,
+                Error::TargetsFailed(b) | Error::DependencyCycle { path: b } if a == b
+            ),
+            Error::InvalidProject(a) => matches!(other, Error::InvalidProject(b) if a == b),
+
+            Error::BadGlob { pattern: a, why }
+            | Error::PkgConfig { package: a, why }
+            | Error::InvalidTarget { name: a, why }
+            | Error::InvalidVersion { version: a, why }
+            | Error::EnvExpansion { value: a, why } => matches!(
+                other,
+                Error::BadGlob { pattern: b, why: other_why }
+                | Error::PkgConfig { package: b, why: other_why }
+                | Error::InvalidTarget { name: b, why: other_why }
+                | Error::InvalidVersion { version: b, why: other_why }
+                | Error::EnvExpansion { value: b, why: other_why }
+                if a == b && why == other_why
+            ),
+
+            Error::TargetPathNotFound { target, path }
+            | Error::SourceNotFound { target, path }
+            | Error::HeaderNotFound { target, path }
+            | Error::IncludeDirNotFound { target, path }
+            | Error::DuplicateTarget { name: target, path } => matches!(
+                other,
+                Error::TargetPathNotFound { target: other_target, path: other_path }
+                | Error::SourceNotFound { target: other_target, path: other_path }
+                | Error::HeaderNotFound { target: other_target, path: other_path }
+                | Error::IncludeDirNotFound { target: other_target, path: other_path }
+                | Error::DuplicateTarget { name: other_target, path: other_path }
+                if target == other_target && path == other_path
+            ),
+            Error::SameObject { first, second } => matches!(
+                other,
+                Error::SameObject { first: other_first, second: other_second }
+                if first == other_first && second == other_second
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_variant_and_fields_are_equal() {
+        assert_eq!(
+            Error::NoSuchBuildTarget("x".into()),
+            Error::NoSuchBuildTarget("x".into())
+        );
+        assert_ne!(
+            Error::NoSuchBuildTarget("x".into()),
+            Error::NoSuchBuildTarget("y".into())
+        );
+    }
+
+    #[test]
+    fn variants_sharing_fields_are_not_equal() {
+        assert_ne!(
+            Error::NoSuchBuildTarget("x".into()),
+            Error::NoSuchProfile("x".into())
+        );
+        assert_ne!(
+            Error::SourceNotFound {
+                target: "x".into(),
+                path: "x.c".into()
+            },
+            Error::HeaderNotFound {
+                target: "x".into(),
+                path: "x.c".into()
+            }
+        );
+    }
+
+    #[test]
+    fn io_errors_compare_by_kind_and_path() {
+        let not_found = || std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(
+            Error::file_io(not_found(), "a"),
+            Error::file_io(not_found(), "a")
+        );
+        assert_ne!(
+            Error::file_io(not_found(), "a"),
+            Error::file_io(not_found(), "b")
+        );
+        assert_ne!(
+            Error::file_io(not_found(), "a"),
+            Error::file_io(std::io::ErrorKind::PermissionDenied.into(), "a")
+        );
+    }
+
+    #[test]
+    fn invalid_project_compares_every_error() {
+        let invalid = |names: &[&str]| {
+            Error::InvalidProject(
+                names
+                    .iter()
+                    .map(|name| Error::NoSources(name.to_string()))
+                    .collect(),
+            )
+        };
+        assert_eq!(invalid(&["a", "b"]), invalid(&["a", "b"]));
+        assert_ne!(invalid(&["a", "b"]), invalid(&["a"]));
+    }
+
+    #[test]
+    fn exit_codes_are_fixed() {
+        assert_eq!(Error::Bug("x".into()).exit_code(), 1);
+        assert_eq!(Error::Cli("x".into()).exit_code(), 2);
+        assert_eq!(Error::NoProject.exit_code(), 3);
+        assert_eq!(Error::NoProjectDir.exit_code(), 3);
+        assert_eq!(Error::NoSuchBuildTarget("x".into()).exit_code(), 4);
+        assert_eq!(Error::CompilerBroken { why: "x".into() }.exit_code(), 5);
+        assert_eq!(Error::TargetsFailed(vec!["x".into()]).exit_code(), 6);
+    }
+}
@@ -1,9 +1,23 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
 
+use compiler::{Compiler, Progress, TargetBuildResult};
 use error::Error;
 use indexmap::IndexMap;
+use project::{Profile, Project, Target, TargetType, UnresolvedProject};
 
+pub mod cache;
 pub mod compiler;
+pub mod deps;
 pub mod error;
 pub mod project;
 
@@ -11,7 +25,18 @@ pub const ENV_VAR_PREFIX: &str = "CR";
 pub const PROJECT_FILENAME: &str = "C.toml";
 pub const CONFIG_DIR_NAME: &str = "cretaceous";
 pub const COMPILERS_FILENAME: &str = "compilers.toml";
+pub const COMPILE_COMMANDS_FILENAME: &str = "compile_commands.json";
+pub const HASH_CACHE_FILENAME: &str = ".cr-cache";
 pub const REPLACE_DEFAULT: &str = "%default";
+pub const DEFAULT_BUILD_DIR: &str = "build";
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[cfg(target_os = "macos")]
+pub const DEFAULT_COMPILER: &str = "clang";
+#[cfg(target_os = "windows")]
+pub const DEFAULT_COMPILER: &str = "msvc";
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+pub const DEFAULT_COMPILER: &str = "gnu";
 
 pub trait UnusedKeys {
     fn unused_keys(&self) -> Vec<String>;
@@ -40,6 +65,12 @@ impl UnusedKeys for String {
     }
 }
 
+impl UnusedKeys for bool {
+    fn unused_keys(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
 impl<T> UnusedKeys for IndexMap<String, T>
 where
     T: UnusedKeys,
@@ -68,7 +99,8 @@ where
     }
 }
 
-pub fn get_env_or<S: AsRef<str>>(vars_in_parts: &[&[S]], or: &str) -> String {
+/// Find the first of `vars_in_parts` which is set, returning its name and value
+fn find_env<S: AsRef<str>>(vars_in_parts: &[&[S]]) -> Option<(String, String)> {
     for var_parts in vars_in_parts {
         let mut name = String::new();
         for part in var_parts.iter() {
@@ -86,31 +118,61 @@ pub fn get_env_or<S: AsRef<str>>(vars_in_parts: &[&[S]], or: &str) -> String {
         }
 
         tracing::trace!("Checking {}", name);
-        if let Some(value) = std::env::var(&name).ok() {
-            return if value.contains(REPLACE_DEFAULT) {
-                let new_value = value.replace("%default", or);
-                tracing::debug!(
-                    "Using {}={:?} (substituted from {:?})",
-                    name,
-                    new_value,
-                    value
-                );
-                new_value
-            } else {
-                tracing::debug!("Using {}={:?}", name, value);
-                value
-            };
+        if let Ok(value) = std::env::var(&name) {
+            return Some((name, value));
         }
     }
 
-    tracing::debug!("Using default: {:?}", or);
-    or.into()
+    None
+}
+
+pub fn get_env_or<S: AsRef<str>>(vars_in_parts: &[&[S]], or: &str) -> String {
+    let Some((name, value)) = find_env(vars_in_parts) else {
+        tracing::debug!("Using default: {:?}", or);
+        return or.into();
+    };
+
+    if value.contains(REPLACE_DEFAULT) {
+        let new_value = value.replace("%default", or);
+        tracing::debug!(
+            "Using {}={:?} (substituted from {:?})",
+            name,
+            new_value,
+            value
+        );
+        new_value
+    } else {
+        tracing::debug!("Using {}={:?}", name, value);
+        value
+    }
+}
+
+/// Like get_env_or, but for values which aren't strings. If the variable can't be parsed, the
+/// default is used instead.
+pub fn get_env_parsed<S: AsRef<str>, T: FromStr + Debug>(vars_in_parts: &[&[S]], or: T) -> T {
+    let Some((name, value)) = find_env(vars_in_parts) else {
+        tracing::debug!("Using default: {:?}", or);
+        return or;
+    };
+
+    match value.parse() {
+        Ok(parsed) => {
+            tracing::debug!("Using {}={:?}", name, parsed);
+            parsed
+        }
+        Err(_) => {
+            tracing::warn!("Invalid value for {}: {:?}, using {:?}", name, value, or);
+            or
+        }
+    }
 }
 
-pub fn find_project_file_from_current_dir() -> Result<PathBuf, Error> {
+/// Search the current directory and its parents for a project file. With `max_depth`, at most
+/// that many parents are searched.
+pub fn find_project_file_from_current_dir(max_depth: Option<usize>) -> Result<PathBuf, Error> {
     let mut dir = std::env::current_dir()?.canonicalize()?;
 
-    loop {
+    for _ in 0..=max_depth.unwrap_or(usize::MAX) {
         for item in dir.read_dir()? {
             let item = item?;
             if item.file_name().to_string_lossy() == crate::PROJECT_FILENAME {
@@ -126,8 +188,71 @@ pub fn find_project_file_from_current_dir() -> Result<PathBuf, Error> {
     Err(Error::NoProject)
 }
 
+const MAIN_C_TEMPLATE: &str = "#include <stdio.h>
+
+int main(void) {
+    printf(\"Hello, world!\\n\");
+    return 0;
+}
+";
+
+/// Create a project file and a hello world program in `dir`, named after the directory
+pub fn init_project(dir: &Path) -> Result<PathBuf, Error> {
+    let project_file = dir.join(PROJECT_FILENAME);
+    if project_file.exists() {
+        return Err(Error::AlreadyExists(project_file.display().to_string()));
+    }
+
+    let name = dir
+        .file_name()
+        .ok_or_else(|| Error::NoFilename(dir.display().to_string()))?
+        .to_string_lossy();
+    let name = toml::Value::String(name.into_owned());
+
+    let project = format!(
+        "[project]
+name = {name}
+version = \"0.1.0\"
+
+[target.{name}]
+type = \"binary\"
+path = \".\"
+sources = \"src/main.c\"
+"
+    );
+    std::fs::write(&project_file, project).map_err(|err| Error::file_io(err, &project_file))?;
+
+    let main_c = dir.join("src").join("main.c");
+    if main_c.exists() {
+        tracing::info!("Not overwriting {}", main_c.display());
+    } else {
+        let src = dir.join("src");
+        std::fs::create_dir_all(&src).map_err(|err| Error::file_io(err, &src))?;
+        std::fs::write(&main_c, MAIN_C_TEMPLATE).map_err(|err| Error::file_io(err, &main_c))?;
+    }
+
+    Ok(project_file)
+}
+
+/// The directory holding compilers.toml: CR_CONFIG_DIR if it's set, otherwise the platform's
+/// config directory (or dist/ in the source tree with the dev feature)
+pub fn config_dir() -> Result<PathBuf, Error> {
+    let dir = macros::env_var!(
+        doc "Directory containing compilers.toml, used instead of the platform's config directory"
+        "config_dir";
+        ""
+    );
+    if !dir.is_empty() {
+        return PathBuf::from(&dir)
+            .canonicalize()
+            .map_err(|io| Error::file_io(io, dir));
+    }
+
+    default_config_dir().ok_or(Error::NoConfigDir)
+}
+
 #[cfg(feature = "dev")]
-pub fn config_dir() -> Option<PathBuf> {
+fn default_config_dir() -> Option<PathBuf> {
     Some(
         PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("dist")
@@ -137,43 +262,482 @@ pub fn config_dir() -> Option<PathBuf> {
 }
 
 #[cfg(not(feature = "dev"))]
-pub fn config_dir() -> Option<PathBuf> {
+fn default_config_dir() -> Option<PathBuf> {
     Some(dirs::config_dir()?.join(CONFIG_DIR_NAME))
 }
 
-pub fn compilers_file() -> Option<PathBuf> {
-    Some(config_dir()?.join(COMPILERS_FILENAME))
+pub const DEFAULT_COMPILERS: &str = include_str!("../dist/compilers.toml");
+
+pub fn compilers_file() -> Result<PathBuf, Error> {
+    Ok(config_dir()?.join(COMPILERS_FILENAME))
+}
+
+/// Write the bundled compilers.toml to the config directory if there isn't one there already
+pub fn write_default_compilers_file() -> Result<PathBuf, Error> {
+    let dir = config_dir()?;
+    let path = dir.join(COMPILERS_FILENAME);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    std::fs::create_dir_all(&dir).map_err(|io| Error::file_io(io, &dir))?;
+    std::fs::write(&path, DEFAULT_COMPILERS).map_err(|io| Error::file_io(io, &path))?;
+    tracing::info!("Wrote default compilers to {}", path.display());
+    Ok(path)
+}
+
+/// Every environment variable the resolve functions check, with its documentation, sorted by
+/// name. Names containing [brackets] are patterns, e.g. [target_name] is replaced with a
+/// target's name.
+pub fn env_vars() -> &'static [(&'static str, &'static str)] {
+    macros::env_var_docs!()
 }
 
 pub fn default_compiler() -> Result<compiler::Compiler, Error> {
-    let compilers_path = compilers_file().ok_or_else(|| Error::NoConfigDir)?;
-    let compilers_str = std::fs::read_to_string(compilers_path.as_path())
-        .map_err(|io| Error::file_io(io, compilers_path.as_path()))?;
+    compiler::Compiler::from_name(DEFAULT_COMPILER)
+}
+
+/// How to tell whether an object file needs to be compiled again. Either way, an object file
+/// compiled with a different command or environment is compiled again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Incremental {
+    /// Compare modification times of the object file and its inputs
+    #[default]
+    Mtime,
+    /// Compare hashes of the inputs' contents with the ones in the build directory's cache
+    Hash,
+}
+
+impl FromStr for Incremental {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mtime" => Ok(Incremental::Mtime),
+            "hash" => Ok(Incremental::Hash),
+            _ => Err(format!("expected mtime or hash, got {:?}", s)),
+        }
+    }
+}
+
+/// How to build a project
+#[derive(Debug, Default, Clone)]
+pub struct BuildOptions {
+    pub debug: bool,
+    pub verbose: bool,
+    pub dry_run: bool,
+    pub force: bool,
+    /// Number of files to compile at once. None or 0 uses every core.
+    pub jobs: Option<usize>,
+    /// Targets to build along with everything they need. Empty builds every target.
+    pub targets: Vec<String>,
+    /// Profile from the project file, or the default profile
+    pub profile: Option<String>,
+    /// Compiler from compilers.toml, or the default compiler
+    pub compiler: Option<String>,
+    /// Sanitizers to compile and link with, like "address" or "undefined"
+    pub sanitize: Vec<String>,
+    /// Keep building targets which don't need a target that failed
+    pub keep_going: bool,
+    /// Treat compiler warnings as errors, unless a target allows them
+    pub werror: bool,
+    /// Environment variables for the compiler, archiver, and linker, after the project's and
+    /// target's
+    pub env: Vec<(String, String)>,
+    /// How to tell whether a file is up to date
+    pub incremental: Incremental,
+}
 
-    let compilers = toml::from_str::<HashMap<String, compiler::CompilerInner>>(&compilers_str)
-        .map_err(|toml| Error::GenericToml {
-            toml,
-            path: compilers_path.display().to_string(),
-        })?;
+impl BuildOptions {
+    pub fn compiler(&self) -> Result<Compiler, Error> {
+        let compiler = match self.compiler.as_deref() {
+            Some(name) => Compiler::from_name(name)?,
+            None => default_compiler()?,
+        };
+        tracing::debug!("Compiler: {:#?}", compiler);
+        Ok(compiler)
+    }
 
-    #[cfg(target_os = "linux")]
-    let default_compiler_name = "gnu";
+    /// Load the default compiler along with every compiler the project's targets ask for, so a
+    /// missing one is found before anything is built
+    pub fn compilers(&self, project: &Project) -> Result<Compilers, Error> {
+        let mut by_name = BTreeMap::new();
+        for name in project
+            .target
+            .values()
+            .filter_map(|target| target.compiler.as_ref())
+        {
+            if !by_name.contains_key(name) {
+                by_name.insert(name.clone(), Compiler::from_name(name)?);
+            }
+        }
 
-    let mut filtered = compilers
-        .into_iter()
-        .map(|(name, inner)| compiler::Compiler { name, inner })
-        .filter(|compiler| compiler.name == default_compiler_name)
-        .collect::<Vec<_>>();
+        Ok(Compilers {
+            default: self.compiler()?,
+            by_name,
+        })
+    }
 
-    match filtered.len() {
-        0 => Err(Error::NoCompiler {
-            name: default_compiler_name.into(),
-        }),
+    pub fn profile(&self, project: &Project) -> Result<Profile, Error> {
+        let mut profile = project.profile(self.profile.as_deref())?;
+        profile.debug |= self.debug;
+        tracing::debug!("Profile: {:#?}", profile);
+        Ok(profile)
+    }
 
-        2.. => Err(Error::ManyCompilers {
-            name: default_compiler_name.into(),
-        }),
+    pub fn jobs(&self) -> usize {
+        let jobs = self.jobs.unwrap_or_else(|| {
+            macros::env_var_parsed!(
+                doc "Number of files to compile at once when -j isn't given (0 to use every core)"
+                "jobs";
+                0
+            )
+        });
+        match jobs {
+            0 => std::thread::available_parallelism()
+                .map(|jobs| jobs.get())
+                .unwrap_or(1),
+            jobs => jobs,
+        }
+    }
 
-        1 => Ok(filtered.pop().unwrap()),
+    pub fn target_levels<'p>(
+        &'p self,
+        project: &'p Project,
+    ) -> Result<Vec<Vec<(&'p str, &'p Target)>>, Error> {
+        if self.targets.is_empty() {
+            project.target_levels()
+        } else {
+            project.target_levels_from(self.targets.iter().map(|name| name.as_str()))
+        }
     }
 }
+
+/// The compilers a project is built with, from BuildOptions::compilers
+#[derive(Debug)]
+pub struct Compilers {
+    default: Compiler,
+    by_name: BTreeMap<String, Compiler>,
+}
+
+impl Compilers {
+    /// The compiler a target asked for, or the default compiler
+    pub fn for_target(&self, target: &Target) -> &Compiler {
+        target
+            .compiler
+            .as_ref()
+            .and_then(|name| self.by_name.get(name))
+            .unwrap_or(&self.default)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Compiler> {
+        std::iter::once(&self.default).chain(self.by_name.values())
+    }
+
+    /// Check that each target's compiler has the tools to build it
+    pub fn check_tools<'t>(
+        &self,
+        project: &Project,
+        targets: impl IntoIterator<Item = &'t Target> + Clone,
+    ) -> Result<(), Error> {
+        for compiler in self.iter() {
+            let targets = targets
+                .clone()
+                .into_iter()
+                .filter(|target| std::ptr::eq(self.for_target(target), compiler));
+            compiler.check_tools(project, targets)?;
+        }
+        Ok(())
+    }
+
+    /// Plan each target with its own compiler
+    pub fn build_plan(
+        &self,
+        project: &Project,
+        targets: &[(&str, &Target)],
+        profile: &Profile,
+        options: &BuildOptions,
+    ) -> Result<Vec<compiler::TargetPlan>, Error> {
+        let mut plan = Vec::new();
+        for target in targets {
+            plan.extend(self.for_target(target.1).build_plan(
+                project,
+                &[*target],
+                profile,
+                options,
+            )?);
+        }
+        Ok(plan)
+    }
+
+    /// Compile commands for each target from its own compiler
+    pub fn compile_commands(
+        &self,
+        project: &Project,
+        targets: &[(&str, &Target)],
+        profile: &Profile,
+        options: &BuildOptions,
+    ) -> Result<Vec<compiler::CompileCommand>, Error> {
+        let mut commands = Vec::new();
+        for target in targets {
+            commands.extend(self.for_target(target.1).compile_commands(
+                project,
+                &[*target],
+                profile,
+                options,
+            )?);
+        }
+        Ok(commands)
+    }
+
+    /// Remove every target's object files and outputs, along with the hash cache
+    pub fn clean(&self, project: &Project, dry_run: bool) -> Result<(), Error> {
+        let mut removed = 0;
+        for compiler in self.iter() {
+            let targets = project
+                .target
+                .values()
+                .filter(|target| std::ptr::eq(self.for_target(target), compiler));
+            removed += compiler.clean(project, targets, dry_run)?;
+        }
+
+        let hash_cache = project.build_dir.join(HASH_CACHE_FILENAME);
+        if hash_cache.exists() {
+            tracing::debug!("Removing {}", hash_cache.display());
+            if !dry_run {
+                std::fs::remove_file(&hash_cache).map_err(|io| Error::file_io(io, &hash_cache))?;
+            }
+            removed += 1;
+        }
+
+        tracing::info!("Removed {} files", removed);
+        Ok(())
+    }
+
+    /// Install each target's outputs, see Compiler::install. If DESTDIR is set, `prefix` is
+    /// placed inside it.
+    pub fn install<'t>(
+        &self,
+        targets: impl IntoIterator<Item = &'t Target> + Clone,
+        prefix: &Path,
+        dry_run: bool,
+    ) -> Result<(), Error> {
+        let destdir = macros::env_var!(
+            doc "Directory to stage an installation in. The --prefix given to cr install is placed inside it"
+            raw "destdir";
+            ""
+        );
+        let prefix = if destdir.is_empty() {
+            prefix.to_path_buf()
+        } else {
+            Path::new(&destdir).join(
+                prefix
+                    .components()
+                    .filter(|component| {
+                        !matches!(component, Component::RootDir | Component::Prefix(_))
+                    })
+                    .collect::<PathBuf>(),
+            )
+        };
+
+        let mut installed = 0;
+        for compiler in self.iter() {
+            let targets = targets
+                .clone()
+                .into_iter()
+                .filter(|target| std::ptr::eq(self.for_target(target), compiler));
+            installed += compiler.install(targets, &prefix, dry_run)?;
+        }
+
+        tracing::info!("Installed {} files to {}", installed, prefix.display());
+        Ok(())
+    }
+
+    pub fn save_hash_caches(&self, options: &BuildOptions) -> Result<(), Error> {
+        self.iter()
+            .try_for_each(|compiler| compiler.save_hash_cache(options))
+    }
+}
+
+/// Read, parse, and resolve a project file
+pub fn load_project(project_file: &Path) -> Result<Project, Error> {
+    tracing::info!("Using project from {}", project_file.display());
+    UnresolvedProject::read(project_file)?.resolve(project_file)
+}
+
+/// Build the project in `project_file`
+pub fn build(project_file: &Path, options: BuildOptions) -> Result<Vec<TargetBuildResult>, Error> {
+    let project = load_project(project_file)?;
+    let levels = options.target_levels(&project)?;
+    build_levels(&project, levels, &options)
+}
+
+/// Build targets level by level, as from Project::target_levels
+pub fn build_levels(
+    project: &Project,
+    levels: Vec<Vec<(&str, &Target)>>,
+    options: &BuildOptions,
+) -> Result<Vec<TargetBuildResult>, Error> {
+    tracing::debug!("Targets: {:#?}", levels);
+    let compilers = options.compilers(project)?;
+    let profile = options.profile(project)?;
+    let jobs = options.jobs();
+    let progress = Progress::new(
+        levels
+            .iter()
+            .flatten()
+            .map(|(_, target)| {
+                let outputs = target
+                    .type_
+                    .iter()
+                    .filter(|type_| **type_ != TargetType::HeaderOnly)
+                    .count();
+                target.sources.len() + outputs
+            })
+            .sum(),
+    );
+
+    for compiler in compilers.iter() {
+        if tracing::enabled!(tracing::Level::DEBUG) {
+            match compiler.detect_version() {
+                Ok(version) => tracing::debug!("{} version: {}", compiler.name, version),
+                Err(err) => tracing::debug!("Could not detect {} version: {}", compiler.name, err),
+            }
+        }
+    }
+
+    if !options.dry_run {
+        compilers.check_tools(project, levels.iter().flatten().map(|(_, target)| *target))?;
+    }
+
+    // with --keep-going, targets which failed or were skipped, and the ones which failed
+    let mut broken = HashSet::new();
+    let mut failed = Vec::new();
+    let mut results = Vec::new();
+
+    for level in levels {
+        let level = level
+            .into_iter()
+            .filter(|(name, target)| {
+                let Some(need) = target
+                    .needs
+                    .iter()
+                    .find(|need| broken.contains(need.as_str()))
+                else {
+                    return true;
+                };
+                tracing::warn!("Skipping {} because {} failed", name, need);
+                broken.insert(*name);
+                false
+            })
+            .collect::<Vec<_>>();
+
+        // split the jobs between the targets in this level, none of which need each other
+        let workers = jobs.clamp(1, level.len().max(1));
+        let target_jobs = (jobs / workers).max(1);
+
+        let next_target = AtomicUsize::new(0);
+        let errors = Mutex::new(Vec::new());
+        let level_results = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    if !options.keep_going && !errors.lock().unwrap().is_empty() {
+                        break;
+                    }
+
+                    let Some((name, target)) =
+                        level.get(next_target.fetch_add(1, Ordering::SeqCst))
+                    else {
+                        break;
+                    };
+
+                    match build_target(
+                        compilers.for_target(target),
+                        project,
+                        target,
+                        &profile,
+                        options,
+                        target_jobs,
+                        &progress,
+                    ) {
+                        Ok(result) => level_results.lock().unwrap().push(result),
+                        Err(err) => {
+                            errors.lock().unwrap().push((*name, err));
+                            if !options.keep_going {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        // the workers finish in any order, so put everything back in the level's order
+        let position = |name: &str| level.iter().position(|(level_name, _)| *level_name == name);
+        let mut errors = errors.into_inner().unwrap();
+        errors.sort_by_key(|(name, _)| position(name));
+        let mut level_results = level_results.into_inner().unwrap();
+        level_results.sort_by_key(|result: &TargetBuildResult| position(&result.name));
+
+        for (name, err) in errors {
+            if !options.keep_going {
+                // files which did compile shouldn't be compiled again
+                compilers.save_hash_caches(options)?;
+                return Err(err);
+            }
+            tracing::error!("{}", err);
+            broken.insert(name);
+            failed.push(name.to_string());
+        }
+
+        results.extend(level_results);
+    }
+
+    compilers.save_hash_caches(options)?;
+
+    if !failed.is_empty() {
+        return Err(Error::TargetsFailed(failed));
+    }
+
+    Ok(results)
+}
+
+fn build_target(
+    compiler: &Compiler,
+    project: &Project,
+    target: &Target,
+    profile: &Profile,
+    options: &BuildOptions,
+    jobs: usize,
+    progress: &Progress,
+) -> Result<TargetBuildResult, Error> {
+    compiler.run_build_commands(target, &target.pre_build, options)?;
+
+    tracing::info!("Compiling target {}", target.name);
+    let mut result = compiler.compile_target(project, target, profile, options, jobs, progress)?;
+
+    for target_type in target.type_.iter() {
+        let Some(output) = compiler.output_filename(target, target_type) else {
+            continue;
+        };
+
+        let start = Instant::now();
+        match target_type {
+            TargetType::Archive => compiler.create_archive(project, target, options, progress)?,
+            TargetType::Dynamic => {
+                compiler.link_dynamic(project, target, profile, options, progress)?
+            }
+            TargetType::Binary => {
+                compiler.link_binary(project, target, profile, options, progress)?
+            }
+            TargetType::HeaderOnly => {}
+        }
+        result.timings.push((output.clone(), start.elapsed()));
+        result.outputs.push(output);
+    }
+
+    compiler.run_build_commands(target, &target.post_build, options)?;
+
+    Ok(result)
+}
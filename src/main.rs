@@ -1,35 +1,446 @@
 use argh::FromArgs;
 use cretaceous::{
+    compiler::Compiler,
     error::Error as CrError,
-    project::{TargetType, UnresolvedProject},
-    UnusedKeys,
+    project::{Project, Target, TargetType, Warning},
+    BuildOptions, Incremental,
+};
+use notify::Watcher;
+use std::{
+    collections::HashSet,
+    io::IsTerminal,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use std::{io::IsTerminal, path::PathBuf};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+/// Options for building a project, given before or after the subcommand
+#[derive(Default)]
+struct BuildArgs {
+    project: Option<PathBuf>,
+    debug: bool,
+    profile: Option<String>,
+    release: bool,
+    verbose: bool,
+    targets: Vec<String>,
+    dry_run: bool,
+    force: bool,
+    incremental: Incremental,
+    emit_compile_commands: bool,
+    emit_script: Option<PathBuf>,
+    compiler: Option<String>,
+    sanitize: Option<String>,
+    keep_going: bool,
+    werror: bool,
+    timings: bool,
+    env: Vec<String>,
+    jobs: Option<usize>,
+    format: Format,
+}
+
+impl BuildArgs {
+    /// Combine the options given after the subcommand with those given before it, preferring
+    /// the ones after
+    fn or(self, global: BuildArgs) -> BuildArgs {
+        BuildArgs {
+            project: self.project.or(global.project),
+            debug: self.debug || global.debug,
+            profile: self.profile.or(global.profile),
+            release: self.release || global.release,
+            verbose: self.verbose || global.verbose,
+            targets: if self.targets.is_empty() {
+                global.targets
+            } else {
+                self.targets
+            },
+            dry_run: self.dry_run || global.dry_run,
+            force: self.force || global.force,
+            incremental: if self.incremental == Incremental::default() {
+                global.incremental
+            } else {
+                self.incremental
+            },
+            emit_compile_commands: self.emit_compile_commands || global.emit_compile_commands,
+            emit_script: self.emit_script.or(global.emit_script),
+            compiler: self.compiler.or(global.compiler),
+            sanitize: self.sanitize.or(global.sanitize),
+            keep_going: self.keep_going || global.keep_going,
+            werror: self.werror || global.werror,
+            timings: self.timings || global.timings,
+            env: global.env.into_iter().chain(self.env).collect(),
+            jobs: self.jobs.or(global.jobs),
+            format: if self.format == Format::default() {
+                global.format
+            } else {
+                self.format
+            },
+        }
+    }
+}
+
+/// Declare a command which takes every option in BuildArgs along with its own fields. argh
+/// can't flatten one struct into another, so the fields are repeated by this instead.
+macro_rules! build_args {
+    (
+        $(#[$meta:meta])*
+        struct $name:ident {
+            $($fields:tt)*
+        }
+    ) => {
+        $(#[$meta])*
+        struct $name {
+            $($fields)*
+
+            #[argh(option, short = 'p', description = "project file")]
+            project: Option<PathBuf>,
+
+            #[argh(switch, short = 'd', description = "build with debug symbols")]
+            debug: bool,
+
+            #[argh(option, description = "profile from the project file to build with")]
+            profile: Option<String>,
+
+            #[argh(switch, description = "build with the release profile")]
+            release: bool,
+
+            #[argh(switch, short = 'v', description = "use verbose output")]
+            verbose: bool,
+
+            #[argh(option, short = 't', description = "build targets")]
+            targets: Vec<String>,
+
+            #[argh(
+                switch,
+                short = 'n',
+                description = "print the commands that would run without running them"
+            )]
+            dry_run: bool,
+
+            #[argh(switch, description = "rebuild files even if they are up to date")]
+            force: bool,
+
+            #[argh(
+                option,
+                default = "Incremental::Mtime",
+                description = "how to tell whether a file is up to date (mtime, hash)"
+            )]
+            incremental: Incremental,
+
+            #[argh(
+                switch,
+                description = "write compile_commands.json to the project directory instead of building"
+            )]
+            emit_compile_commands: bool,
+
+            #[argh(
+                option,
+                description = "with --dry-run, write the build's commands to a shell script"
+            )]
+            emit_script: Option<PathBuf>,
+
+            #[argh(option, description = "compiler from compilers.toml to build with")]
+            compiler: Option<String>,
+
+            #[argh(
+                option,
+                description = "comma-separated sanitizers to build with, e.g. address,undefined"
+            )]
+            sanitize: Option<String>,
+
+            #[argh(
+                switch,
+                short = 'k',
+                description = "keep building targets which don't need a failed target"
+            )]
+            keep_going: bool,
+
+            #[argh(switch, description = "treat compiler warnings as errors")]
+            werror: bool,
+
+            #[argh(switch, description = "show how long each file took to build")]
+            timings: bool,
+
+            #[argh(
+                option,
+                description = "set an environment variable for the compiler and linker, as KEY=VALUE"
+            )]
+            env: Vec<String>,
+
+            #[argh(
+                option,
+                short = 'j',
+                description = "number of files to compile at once (0 to use every core)"
+            )]
+            jobs: Option<usize>,
+
+            #[argh(
+                option,
+                default = "Format::Human",
+                from_str_fn(parse_format),
+                description = "with --dry-run, how to show what would be built (human, json)"
+            )]
+            format: Format,
+        }
+
+        impl $name {
+            fn build_args(&self) -> BuildArgs {
+                BuildArgs {
+                    project: self.project.clone(),
+                    debug: self.debug,
+                    profile: self.profile.clone(),
+                    release: self.release,
+                    verbose: self.verbose,
+                    targets: self.targets.clone(),
+                    dry_run: self.dry_run,
+                    force: self.force,
+                    incremental: self.incremental,
+                    emit_compile_commands: self.emit_compile_commands,
+                    emit_script: self.emit_script.clone(),
+                    compiler: self.compiler.clone(),
+                    sanitize: self.sanitize.clone(),
+                    keep_going: self.keep_going,
+                    werror: self.werror,
+                    timings: self.timings,
+                    env: self.env.clone(),
+                    jobs: self.jobs,
+                    format: self.format,
+                }
+            }
+        }
+    };
+}
+
+build_args! {
+    #[derive(argh::FromArgs)]
+    #[argh(
+        description = "build tool xd",
+        error_code(1, "Unexpected error, such as an I/O error."),
+        error_code(2, "Invalid command line."),
+        error_code(3, "No project file found."),
+        error_code(4, "Invalid project file."),
+        error_code(5, "Invalid or unusable compiler configuration."),
+        error_code(6, "Compiling, archiving, or linking failed.")
+    )]
+    struct Args {
+        #[argh(
+            option,
+            short = 'C',
+            description = "change to this directory before doing anything"
+        )]
+        directory: Option<PathBuf>,
+
+        // `cr path/to/C.toml` from before there were subcommands
+        #[argh(positional, hidden_help)]
+        project_file: Option<PathBuf>,
+
+        #[argh(switch, description = "list the targets in the project")]
+        list_targets: bool,
+
+        #[argh(
+            option,
+            default = "Format::Human",
+            from_str_fn(parse_error_format),
+            description = "how to report errors and warnings (human, json)"
+        )]
+        error_format: Format,
+
+        #[argh(subcommand)]
+        command: Option<Command>,
+    }
+}
+
 #[derive(argh::FromArgs)]
-#[argh(description = "build tool xd")]
-struct Args {
-    #[argh(positional, description = "project file")]
+#[argh(subcommand)]
+enum Command {
+    Build(Build),
+    Init(Init),
+    Compilers(Compilers),
+    Env(Env),
+    Check(Check),
+    Clean(Clean),
+    Install(Install),
+    Graph(Graph),
+    Watch(Watch),
+    Tree(Tree),
+    Run(Run),
+}
+
+impl Command {
+    /// The options given after the subcommand. Subcommands which don't build anything can
+    /// still name the project file.
+    fn build_args(&self) -> BuildArgs {
+        match self {
+            Command::Build(build) => build.build_args(),
+            Command::Check(check) => check.build_args(),
+            Command::Clean(clean) => clean.build_args(),
+            Command::Install(install) => install.build_args(),
+            Command::Watch(watch) => watch.build_args(),
+            Command::Run(run) => run.build_args(),
+            Command::Graph(Graph { project }) | Command::Tree(Tree { project }) => BuildArgs {
+                project: project.clone(),
+                ..Default::default()
+            },
+            Command::Init(_) | Command::Compilers(_) | Command::Env(_) => BuildArgs::default(),
+        }
+    }
+}
+
+build_args! {
+    #[derive(argh::FromArgs)]
+    #[argh(
+        subcommand,
+        name = "build",
+        description = "build the project (the default)"
+    )]
+    struct Build {}
+}
+
+build_args! {
+    #[derive(argh::FromArgs)]
+    #[argh(
+        subcommand,
+        name = "watch",
+        description = "build the project, then rebuild whenever a source or header changes"
+    )]
+    struct Watch {}
+}
+
+#[derive(argh::FromArgs)]
+#[argh(
+    subcommand,
+    name = "graph",
+    description = "print the dependencies between targets in Graphviz DOT format"
+)]
+struct Graph {
+    #[argh(option, short = 'p', description = "project file")]
     project: Option<PathBuf>,
+}
 
-    #[argh(switch, short = 'd', description = "build with debug symbols")]
-    debug: bool,
+#[derive(argh::FromArgs)]
+#[argh(
+    subcommand,
+    name = "tree",
+    description = "print the targets each target needs"
+)]
+struct Tree {
+    #[argh(option, short = 'p', description = "project file")]
+    project: Option<PathBuf>,
+}
 
-    #[argh(switch, short = 'v', description = "use verbose output")]
-    verbose: bool,
+#[derive(argh::FromArgs)]
+#[argh(
+    subcommand,
+    name = "init",
+    description = "create a new project in the current directory"
+)]
+struct Init {}
 
-    #[argh(option, short = 't', description = "build targets")]
-    targets: Vec<String>,
+#[derive(argh::FromArgs)]
+#[argh(
+    subcommand,
+    name = "compilers",
+    description = "list the compilers in compilers.toml"
+)]
+struct Compilers {}
 
-    #[argh(switch, description = "don't actually do anything")]
-    dry_run: bool,
+#[derive(argh::FromArgs)]
+#[argh(
+    subcommand,
+    name = "env",
+    description = "list the environment variables which override compilers.toml"
+)]
+struct Env {}
+
+build_args! {
+    #[derive(argh::FromArgs)]
+    #[argh(
+        subcommand,
+        name = "check",
+        description = "check that the project file is valid without building"
+    )]
+    struct Check {}
+}
+
+build_args! {
+    #[derive(argh::FromArgs)]
+    #[argh(
+        subcommand,
+        name = "clean",
+        description = "remove object files and link outputs"
+    )]
+    struct Clean {}
+}
+
+build_args! {
+    #[derive(argh::FromArgs)]
+    #[argh(
+        subcommand,
+        name = "install",
+        description = "copy built binaries, libraries, and headers to a prefix"
+    )]
+    struct Install {
+        #[argh(option, description = "directory to install to, e.g. /usr/local")]
+        prefix: PathBuf,
+    }
+}
+
+build_args! {
+    #[derive(argh::FromArgs)]
+    #[argh(
+        subcommand,
+        name = "run",
+        description = "build a binary target and run it"
+    )]
+    struct Run {
+        #[argh(positional, description = "binary target to run")]
+        target: String,
+
+        #[argh(positional, greedy, description = "arguments for the program")]
+        args: Vec<String>,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum Format {
+    #[default]
+    Human,
+    Json,
+}
+
+impl Format {
+    fn parse(value: &str, option: &str) -> Result<Format, String> {
+        match value {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "Unknown {} {:?}, expected human or json",
+                option, value
+            )),
+        }
+    }
+}
+
+fn parse_format(value: &str) -> Result<Format, String> {
+    Format::parse(value, "--format")
+}
+
+fn parse_error_format(value: &str) -> Result<Format, String> {
+    Format::parse(value, "--error-format")
 }
 
 fn main() {
-    let layer = tracing_subscriber::fmt::layer().without_time().compact();
-    let layer = if !std::io::stdout().is_terminal() {
+    // logs go to stderr so output like cr graph can be piped
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .without_time()
+        .compact();
+    let layer = if !std::io::stderr().is_terminal() {
         layer.with_ansi(false)
     } else {
         layer
@@ -49,25 +460,66 @@ fn main() {
         .with(layer)
         .init();
 
-    match run() {
-        Ok(()) => {}
-        Err(err) => {
-            use std::error::Error;
+    let args = match parse_args() {
+        Ok(Some(args)) => args,
+        Ok(None) => return,
+        Err(err) => report_error(&err, Format::Human),
+    };
+
+    let error_format = args.error_format;
+    if let Err(err) = run(args) {
+        report_error(&err, error_format);
+    }
+}
+
+fn report_error(err: &CrError, error_format: Format) -> ! {
+    use std::error::Error;
 
-            tracing::error!("{}", err);
+    let mut sources = Vec::new();
+    let mut source = err.source();
+    while let Some(the_source) = source {
+        sources.push(the_source.to_string());
+        source = the_source.source();
+    }
 
-            let mut source = err.source();
-            while let Some(the_source) = source {
-                tracing::error!("Because of: {}", the_source);
-                source = the_source.source();
+    match error_format {
+        Format::Human => {
+            tracing::error!("{}", err);
+            for source in sources {
+                tracing::error!("Because of: {}", source);
             }
+        }
 
-            std::process::exit(1);
+        Format::Json => {
+            let json = serde_json::json!({
+                "kind": err.kind(),
+                "category": err.category(),
+                "message": err.to_string(),
+                "sources": sources,
+            });
+            eprintln!("{}", json);
         }
     }
+
+    std::process::exit(err.exit_code());
 }
 
-fn run() -> Result<(), CrError> {
+fn report_warnings(warnings: &[Warning], error_format: Format) {
+    for warning in warnings {
+        match error_format {
+            Format::Human => tracing::warn!("{}", warning),
+            Format::Json => {
+                let json = serde_json::json!({
+                    "warning": warning,
+                    "message": warning.to_string(),
+                });
+                eprintln!("{}", json);
+            }
+        }
+    }
+}
+
+fn parse_args() -> Result<Option<Args>, CrError> {
     let arg_strings = std::env::args().collect::<Vec<_>>();
     let arg_strs = arg_strings.iter().map(String::as_str).collect::<Vec<_>>();
     let args = match Args::from_args(&arg_strs[0..1], &arg_strs[1..]) {
@@ -79,82 +531,456 @@ fn run() -> Result<(), CrError> {
                 tracing::info!(
                     "\n{}\n{}",
                     exit.output,
-                    if let Ok(project_file) = cretaceous::find_project_file_from_current_dir() {
+                    if let Ok(project_file) = cretaceous::find_project_file_from_current_dir(None) {
                         format!("There is a project at {}", project_file.display())
                     } else {
                         "No project in current directory (or any parent directory)".into()
                     },
                 );
-                return Ok(());
+                return Ok(None);
             }
         }
     };
 
-    let project_file = match args.project {
+    Ok(Some(args))
+}
+
+fn run(args: Args) -> Result<(), CrError> {
+    if let Some(directory) = args.directory.as_ref() {
+        let directory = directory
+            .canonicalize()
+            .map_err(|io| CrError::file_io(io, directory))?;
+        std::env::set_current_dir(&directory).map_err(|io| CrError::file_io(io, &directory))?;
+        tracing::debug!("Changed directory to {}", directory.display());
+    }
+
+    if let Some(Command::Init(_)) = args.command {
+        let dir = std::env::current_dir()?.canonicalize()?;
+        let project_file = cretaceous::init_project(&dir)?;
+        tracing::info!("Created {}", project_file.display());
+        return Ok(());
+    }
+
+    if let Some(Command::Compilers(_)) = args.command {
+        for compiler in Compiler::load_all()? {
+            println!(
+                "{}{}: compile with {}, link with {}, version {}",
+                compiler.name,
+                if compiler.name == cretaceous::DEFAULT_COMPILER {
+                    " (default)"
+                } else {
+                    ""
+                },
+                compiler.compile_command,
+                compiler.link_command,
+                compiler
+                    .detect_version()
+                    .unwrap_or_else(|_| String::from("not installed")),
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Env(_)) = args.command {
+        for (name, doc) in cretaceous::env_vars() {
+            println!("{}", name);
+            println!("    {}", doc);
+            if let Ok(value) = std::env::var(name) {
+                println!("    Currently set to {:?}", value);
+            }
+        }
+        return Ok(());
+    }
+
+    let build_args = args
+        .command
+        .as_ref()
+        .map(Command::build_args)
+        .unwrap_or_default()
+        .or(args.build_args());
+    let options = build_options(&build_args)?;
+    let project = cretaceous::load_project(&project_file(&args, &build_args)?)?;
+    report_warnings(&project.warnings, args.error_format);
+    tracing::debug!("Project meta: {:#?}", project.project);
+
+    if args.list_targets {
+        for target in project.target.values() {
+            let mut types = target
+                .type_
+                .iter()
+                .map(|type_| type_.to_string())
+                .collect::<Vec<_>>();
+            types.sort();
+            println!(
+                "{} ({}), {} sources",
+                target.name,
+                types.join(", "),
+                target.sources.len()
+            );
+        }
+        return Ok(());
+    }
+
+    match &args.command {
+        None | Some(Command::Build(_)) => {
+            let levels = options.target_levels(&project)?;
+            build(&build_args, &options, &project, levels)
+        }
+
+        Some(Command::Run(run)) => {
+            let target = project
+                .target
+                .get(run.target.as_str())
+                .ok_or_else(|| CrError::NoSuchBuildTarget(run.target.clone()))?;
+            if !target.type_.contains(&TargetType::Binary) {
+                return Err(CrError::NotBinary(target.name.clone()));
+            }
+
+            let levels = project.target_levels_from(std::iter::once(run.target.as_str()))?;
+            build(&build_args, &options, &project, levels)?;
+            run_binary(&options, &project, target, &run.args)
+        }
+
+        Some(Command::Check(_)) => {
+            options
+                .compilers(&project)?
+                .check_tools(&project, project.target.values())?;
+            options.profile(&project)?;
+            tracing::info!("Project {} is valid", project.project.name);
+            Ok(())
+        }
+
+        Some(Command::Clean(_)) => options
+            .compilers(&project)?
+            .clean(&project, options.dry_run),
+
+        Some(Command::Install(install)) => {
+            let targets = if options.targets.is_empty() {
+                project.target.values().collect::<Vec<_>>()
+            } else {
+                options
+                    .targets
+                    .iter()
+                    .map(|name| {
+                        project
+                            .target
+                            .get(name.as_str())
+                            .ok_or_else(|| CrError::NoSuchBuildTarget(name.clone()))
+                    })
+                    .collect::<Result<_, _>>()?
+            };
+            options
+                .compilers(&project)?
+                .install(targets, &install.prefix, options.dry_run)
+        }
+
+        Some(Command::Tree(_)) => {
+            print!("{}", project.dependency_tree());
+            Ok(())
+        }
+
+        Some(Command::Watch(_)) => watch(&build_args, &options, project, args.error_format),
+
+        Some(Command::Graph(_)) => {
+            print!("{}", project.dependency_graph());
+            Ok(())
+        }
+
+        Some(Command::Init(_)) | Some(Command::Compilers(_)) | Some(Command::Env(_)) => Err(
+            CrError::Bug("Command should have been handled without a project".into()),
+        ),
+    }
+}
+
+fn project_file(args: &Args, build_args: &BuildArgs) -> Result<PathBuf, CrError> {
+    match build_args.project.as_ref().or(args.project_file.as_ref()) {
         Some(project_file) => project_file
             .canonicalize()
-            .map_err(|io| CrError::file_io(io, project_file.as_path()))?,
-        None => cretaceous::find_project_file_from_current_dir()?,
+            .map_err(|io| CrError::file_io(io, project_file.as_path())),
+        None => cretaceous::find_project_file_from_current_dir(None),
+    }
+}
+
+fn build_options(args: &BuildArgs) -> Result<BuildOptions, CrError> {
+    let profile = match (args.profile.as_ref(), args.release) {
+        (Some(_), true) => {
+            return Err(CrError::Cli(
+                "--profile and --release can't be used together".into(),
+            ))
+        }
+        (Some(name), false) => Some(name.clone()),
+        (None, true) => Some("release".into()),
+        (None, false) => None,
     };
-    let project_dir = project_file.parent().ok_or_else(|| CrError::NoProjectDir)?;
 
-    tracing::info!("Building project from {}", project_file.display());
+    let env = args
+        .env
+        .iter()
+        .map(|var| match var.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.into(), value.into())),
+            _ => Err(CrError::Cli(format!(
+                "--env needs KEY=VALUE, got {:?}",
+                var
+            ))),
+        })
+        .collect::<Result<_, _>>()?;
 
-    let file = std::fs::read_to_string(project_file.as_path())?;
-    let parsed_project: UnresolvedProject =
-        toml::from_str(&file).map_err(|toml| CrError::ReadProject {
-            toml,
-            path: project_file.display().to_string(),
-        })?;
+    Ok(BuildOptions {
+        debug: args.debug,
+        verbose: args.verbose,
+        dry_run: args.dry_run,
+        force: args.force,
+        jobs: args.jobs,
+        targets: args.targets.clone(),
+        profile,
+        compiler: args.compiler.clone(),
+        sanitize: args
+            .sanitize
+            .iter()
+            .flat_map(|sanitize| sanitize.split(','))
+            .filter(|sanitizer| !sanitizer.is_empty())
+            .map(String::from)
+            .collect(),
+        keep_going: args.keep_going,
+        werror: args.werror,
+        env,
+        incremental: args.incremental,
+    })
+}
 
-    let unused = parsed_project.unused_keys();
-    if !unused.is_empty() {
-        tracing::warn!("Unused keys: {:?}", unused);
+fn build(
+    args: &BuildArgs,
+    options: &BuildOptions,
+    project: &Project,
+    levels: Vec<Vec<(&str, &Target)>>,
+) -> Result<(), CrError> {
+    if args.emit_compile_commands {
+        let compilers = options.compilers(project)?;
+        let profile = options.profile(project)?;
+        let targets = levels.into_iter().flatten().collect::<Vec<_>>();
+        let commands = compilers.compile_commands(project, &targets, &profile, options)?;
+        let path = project.dir.join(cretaceous::COMPILE_COMMANDS_FILENAME);
+        let json = serde_json::to_string_pretty(&commands)
+            .map_err(|err| CrError::Bug(format!("Could not serialize compile commands: {err}")))?;
+        std::fs::write(&path, json).map_err(|io| CrError::file_io(io, &path))?;
+        tracing::info!("Wrote {}", path.display());
+        return Ok(());
     }
 
-    let project = parsed_project.resolve(project_dir)?;
-    let compiler = cretaceous::default_compiler()?;
-    tracing::debug!("Project meta: {:#?}", project.project);
-    tracing::debug!("Compiler: {:#?}", compiler);
+    if let Some(path) = args.emit_script.as_ref() {
+        if !args.dry_run {
+            return Err(CrError::Cli("--emit-script needs --dry-run".into()));
+        }
 
-    let targets = if args.targets.is_empty() {
-        project.targets_in_order()?
-    } else {
-        project.targets_in_order_from(args.targets.iter().map(|name| name.as_str()))?
+        let compilers = options.compilers(project)?;
+        let profile = options.profile(project)?;
+        let targets = levels.into_iter().flatten().collect::<Vec<_>>();
+        let plan = compilers.build_plan(project, &targets, &profile, options)?;
+        std::fs::write(path, cretaceous::compiler::shell_script(&plan))
+            .map_err(|io| CrError::file_io(io, path))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+                .map_err(|io| CrError::file_io(io, path))?;
+        }
+        tracing::info!("Wrote {}", path.display());
+        return Ok(());
+    }
+
+    if args.format == Format::Json {
+        if !args.dry_run {
+            return Err(CrError::Cli("--format json needs --dry-run".into()));
+        }
+
+        let compilers = options.compilers(project)?;
+        let profile = options.profile(project)?;
+        let targets = levels.into_iter().flatten().collect::<Vec<_>>();
+        let plan = compilers.build_plan(project, &targets, &profile, options)?;
+        let json = serde_json::to_string_pretty(&plan)
+            .map_err(|err| CrError::Bug(format!("Could not serialize build plan: {err}")))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let results = cretaceous::build_levels(project, levels, options)?;
+
+    if args.timings {
+        let mut timings = results
+            .iter()
+            .flat_map(|result| result.timings.iter())
+            .collect::<Vec<_>>();
+        timings.sort_by(|(_, a), (_, b)| b.cmp(a));
+        for (path, duration) in timings {
+            tracing::info!(
+                "{:>8.3}s {}",
+                duration.as_secs_f64(),
+                path.strip_prefix(&project.dir).unwrap_or(path).display()
+            );
+        }
+    }
+
+    tracing::info!(
+        "Finished in {:.1}s ({} compiled, {} up to date)",
+        start.elapsed().as_secs_f64(),
+        results
+            .iter()
+            .map(|result| result.compiled.len())
+            .sum::<usize>(),
+        results
+            .iter()
+            .map(|result| result.skipped.len())
+            .sum::<usize>(),
+    );
+    Ok(())
+}
+
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+fn watch(
+    args: &BuildArgs,
+    options: &BuildOptions,
+    mut project: Project,
+    error_format: Format,
+) -> Result<(), CrError> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_handler = Arc::clone(&stop);
+    ctrlc::set_handler(move || stop_handler.store(true, Ordering::SeqCst))
+        .map_err(|err| CrError::Bug(format!("Could not handle Ctrl-C: {err}")))?;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let (mut watcher, mut watched) = watch_project(&project, sender.clone())?;
+
+    let result = options
+        .target_levels(&project)
+        .and_then(|levels| build(args, options, &project, levels));
+    if let Err(err) = result {
+        tracing::error!("{}", err);
+    }
+    tracing::info!("Watching for changes, press Ctrl-C to stop");
+
+    let collect = |event: notify::Result<notify::Event>,
+                   watched: &HashSet<PathBuf>,
+                   changed: &mut HashSet<PathBuf>| match event {
+        Ok(event) if !event.kind.is_access() => changed.extend(
+            event
+                .paths
+                .into_iter()
+                .filter(|path| watched.contains(path)),
+        ),
+        Ok(_) => {}
+        Err(err) => tracing::warn!("{}", err),
     };
-    tracing::debug!("Targets: {:#?}", targets);
 
-    for (_, target) in targets {
-        tracing::info!("Compiling target {}", target.name);
-        compiler.compile_target(&project, target, args.debug, args.verbose, args.dry_run)?;
+    while !stop.load(Ordering::SeqCst) {
+        let mut changed = HashSet::new();
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(event) => collect(event, &watched, &mut changed),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
 
-        for target_type in target.type_.iter() {
-            match target_type {
-                TargetType::Archive => {
-                    compiler.create_archive(&project, target, args.verbose, args.dry_run)?;
-                }
+        // wait for things to settle so saving several files only rebuilds once
+        while let Ok(event) = receiver.recv_timeout(WATCH_DEBOUNCE) {
+            collect(event, &watched, &mut changed);
+        }
 
-                TargetType::Dynamic => {
-                    compiler.link_dynamic(
-                        &project,
-                        target,
-                        args.verbose,
-                        args.debug,
-                        args.dry_run,
-                    )?;
-                }
+        if changed.is_empty() {
+            continue;
+        }
+
+        for path in changed.iter() {
+            tracing::info!("{} changed", path.display());
+        }
 
-                TargetType::Binary => {
-                    compiler.link_binary(
-                        &project,
-                        target,
-                        args.verbose,
-                        args.debug,
-                        args.dry_run,
-                    )?;
+        // targets, sources, and flags may all have changed, so start over from the new project
+        if changed.contains(&project.file) {
+            match cretaceous::load_project(&project.file) {
+                Ok(reloaded) => {
+                    report_warnings(&reloaded.warnings, error_format);
+                    project = reloaded;
+                    (watcher, watched) = watch_project(&project, sender.clone())?;
+                }
+                Err(err) => {
+                    tracing::error!("{}", err);
+                    continue;
                 }
             }
         }
+
+        let result = options.target_levels(&project).and_then(|levels| {
+            let selected = levels
+                .into_iter()
+                .flatten()
+                .map(|(name, _)| name)
+                .collect::<HashSet<_>>();
+            let affected = project
+                .targets_affected_by(&changed)
+                .into_iter()
+                .filter(|name| selected.contains(name));
+            project
+                .target_levels_from(affected)
+                .and_then(|levels| build(args, options, &project, levels))
+        });
+        if let Err(err) = result {
+            tracing::error!("{}", err);
+        }
+    }
+
+    drop(watcher);
+    tracing::info!("Stopped watching");
+    Ok(())
+}
+
+/// Watch the project file and every source and header, returning the watcher along with the
+/// files it is watching
+fn watch_project(
+    project: &Project,
+    sender: std::sync::mpsc::Sender<notify::Result<notify::Event>>,
+) -> Result<(notify::RecommendedWatcher, HashSet<PathBuf>), CrError> {
+    let mut watched = HashSet::new();
+    watched.insert(project.file.clone());
+    for target in project.target.values() {
+        watched.extend(target.sources.iter().cloned());
+        watched.extend(target.headers.iter().cloned());
+    }
+
+    // editors often replace files rather than writing to them, so watch the directories
+    let mut watcher = notify::recommended_watcher(sender)?;
+    let dirs = watched
+        .iter()
+        .filter_map(|file| file.parent())
+        .collect::<HashSet<_>>();
+    for dir in dirs {
+        watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+    }
+
+    Ok((watcher, watched))
+}
+
+fn run_binary(
+    options: &BuildOptions,
+    project: &Project,
+    target: &Target,
+    program_args: &[String],
+) -> Result<(), CrError> {
+    let binary = options
+        .compilers(project)?
+        .for_target(target)
+        .binary_output_filename(target);
+    tracing::info!("Running {}", binary.display());
+    if options.dry_run {
+        tracing::debug!("Skipping due to --dry-run");
+        return Ok(());
+    }
+
+    let status = subprocess::Exec::cmd(&binary).args(program_args).join()?;
+    if !status.success() {
+        std::process::exit(match status {
+            subprocess::ExitStatus::Exited(code) => code as i32,
+            _ => 1,
+        });
     }
 
     Ok(())
@@ -11,8 +11,17 @@ use std::{
 #[derive(macros::UnusedKeys, serde::Deserialize, Debug)]
 pub struct UnresolvedProject {
     pub project: ProjectMeta,
+
+    #[serde(default)]
     pub target: IndexMap<String, UnresolvedTarget>,
 
+    pub workspace: Option<Workspace>,
+
+    pub defaults: Option<Defaults>,
+
+    #[serde(default)]
+    pub profile: IndexMap<String, Profile>,
+
     #[serde(flatten)]
     #[unused]
     pub rest: HashMap<String, toml::Value>,
@@ -20,54 +29,537 @@ pub struct UnresolvedProject {
 
 #[derive(Debug)]
 pub struct Project {
+    pub file: PathBuf,
     pub dir: PathBuf,
+    pub build_dir: PathBuf,
+    /// The project's version, parsed from `project.version`
+    pub version: semver::Version,
     pub project: ProjectMeta,
     pub target: IndexMap<String, Target>,
+    pub profile: IndexMap<String, Profile>,
+    /// Problems which didn't stop the project from resolving
+    pub warnings: Vec<Warning>,
+}
+
+/// Something suspicious about a project, which is reported but doesn't stop it from building
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Warning {
+    UnusedKeys {
+        path: PathBuf,
+        keys: Vec<String>,
+    },
+    DuplicateFile {
+        target: String,
+        what: String,
+        path: PathBuf,
+    },
+    SkippedTarget {
+        target: String,
+        platforms: Vec<String>,
+    },
+    IgnoredMemberSettings {
+        path: PathBuf,
+        keys: Vec<String>,
+    },
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::UnusedKeys { path, keys } => {
+                write!(f, "Unused keys in {}: {:?}", path.display(), keys)
+            }
+            Warning::DuplicateFile { target, what, path } => write!(
+                f,
+                "Ignoring {} {} listed more than once in {}",
+                what,
+                path.display(),
+                target
+            ),
+            Warning::SkippedTarget { target, platforms } => write!(
+                f,
+                "Skipping target {}, which is only for {}",
+                target,
+                platforms.join(", ")
+            ),
+            Warning::IgnoredMemberSettings { path, keys } => write!(
+                f,
+                "Ignoring {} in workspace member {}, which only apply when it's built on its own",
+                keys.join(", "),
+                path.display()
+            ),
+        }
+    }
 }
 
 impl UnresolvedProject {
-    pub fn resolve(self, project_dir: &Path) -> Result<Project, Error> {
+    /// Read and parse a project file
+    pub fn read(project_file: &Path) -> Result<UnresolvedProject, Error> {
+        let file =
+            std::fs::read_to_string(project_file).map_err(|io| Error::file_io(io, project_file))?;
+        let parsed_project: UnresolvedProject =
+            toml::from_str(&file).map_err(|toml| Error::ReadProject {
+                toml: Box::new(toml),
+                path: project_file.display().to_string(),
+            })?;
+
+        Ok(parsed_project)
+    }
+
+    /// Check for mistakes which can be found without looking at the filesystem, reporting all
+    /// of them at once. Target names are already unique, since TOML rejects duplicate keys.
+    pub fn validate(&self) -> Result<(), Vec<Error>> {
+        let mut errors = self.validate_targets();
+
+        if let Err(err) = self.project.parse_version() {
+            errors.push(err);
+        }
+
+        // a workspace's targets can need its members' targets, which aren't known until the
+        // members are read
+        if self.workspace.is_none() {
+            let needs = self.target.values().flat_map(|target| target.needs.iter());
+            for need in needs.chain(self.project.default_targets.iter()) {
+                if !self.target.contains_key(need) {
+                    errors.push(Error::NoSuchBuildTarget(need.clone()));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn validate_targets(&self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        for (name, target) in self.target.iter() {
+            let header_only = target.type_.contains(&TargetType::HeaderOnly);
+
+            // compiling nothing and linking nothing is almost always a mistake
+            if target.sources.is_empty() && !header_only {
+                errors.push(Error::NoSources(name.clone()));
+            }
+
+            let mut invalid = |why: &str| {
+                errors.push(Error::InvalidTarget {
+                    name: name.clone(),
+                    why: why.into(),
+                })
+            };
+
+            if target.type_.is_empty() {
+                invalid("no type");
+            }
+            if target.type_.contains(&TargetType::Binary)
+                && target.type_.contains(&TargetType::Dynamic)
+            {
+                invalid("it can't be both a binary and a dynamic library");
+            }
+            if header_only
+                && target
+                    .type_
+                    .iter()
+                    .any(|type_| *type_ != TargetType::HeaderOnly)
+            {
+                invalid("a header-only target can't have another type");
+            }
+            if header_only && !target.sources.is_empty() {
+                invalid("a header-only target can't have sources");
+            }
+
+            let mut types = HashSet::new();
+            for type_ in target.type_.iter() {
+                if !types.insert(type_) {
+                    invalid(&format!("{} is listed more than once", type_));
+                }
+            }
+            if target.needs.iter().any(|need| need == name) {
+                invalid("it needs itself");
+            }
+
+            let mut needs = HashSet::new();
+            for need in target.needs.iter() {
+                if !needs.insert(need) {
+                    invalid(&format!("{} is needed more than once", need));
+                }
+            }
+        }
+        errors
+    }
+
+    /// Merge the defaults table into every target. Values the target sets itself are kept, except
+    /// lists, which are appended to the defaults. `type` is a list, but is replaced instead.
+    pub fn apply_defaults(&mut self) {
+        let Some(defaults) = self.defaults.take() else {
+            return;
+        };
+
+        fn append(defaults: &[String], target: &mut Vec<String>) {
+            let mut merged = defaults.to_vec();
+            merged.append(target);
+            *target = merged;
+        }
+
+        for target in self.target.values_mut() {
+            if target.type_.is_empty() {
+                target.type_ = defaults.type_.clone();
+            }
+            target.compiler = target.compiler.take().or_else(|| defaults.compiler.clone());
+            target.std = target.std.take().or_else(|| defaults.std.clone());
+            target.cxx_std = target.cxx_std.take().or_else(|| defaults.cxx_std.clone());
+            target.warnings = target.warnings.or(defaults.warnings);
+
+            append(&defaults.include_dirs, &mut target.include_dirs);
+            append(&defaults.cflags, &mut target.cflags);
+            append(&defaults.pkg_config, &mut target.pkg_config);
+            append(&defaults.frameworks, &mut target.frameworks);
+            append(&defaults.defines, &mut target.defines);
+
+            let mut env = defaults.env.clone();
+            env.extend(std::mem::take(&mut target.env));
+            target.env = env;
+        }
+    }
+
+    pub fn resolve(mut self, project_file: &Path) -> Result<Project, Error> {
+        self.apply_defaults();
+        self.validate().map_err(Error::InvalidProject)?;
+        let mut warnings = Vec::new();
+        let mut project =
+            self.resolve_with_members(project_file, &mut Vec::new(), &mut warnings)?;
+        project.warnings = warnings;
+
+        // targets for other platforms are gone, so nothing should wait on them
+        let dropped = project
+            .warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                Warning::SkippedTarget { target, .. } => Some(target.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for target in project.target.values_mut() {
+            target.needs.retain(|need| !dropped.contains(need));
+        }
+        project
+            .project
+            .default_targets
+            .retain(|name| !dropped.contains(name));
+
+        // only checked once every member is resolved, since members can need each other
+        let needs = project
+            .target
+            .values()
+            .flat_map(|target| target.needs.iter());
+        for need in needs.chain(project.project.default_targets.iter()) {
+            if !project.target.contains_key(need) {
+                return Err(Error::NoSuchBuildTarget(need.clone()));
+            }
+        }
+
+        // objects are named after the source without its extension, so foo.c and foo.cpp in the
+        // same directory would overwrite each other's object
+        let mut objects = HashMap::new();
+        for source in project
+            .target
+            .values()
+            .flat_map(|target| target.sources.iter())
+        {
+            let first = objects.entry(source.with_extension("")).or_insert(source);
+            if *first != source {
+                return Err(Error::SameObject {
+                    first: first.display().to_string(),
+                    second: source.display().to_string(),
+                });
+            }
+        }
+
+        let mut done = HashSet::new();
+        for target_name in project.target.keys() {
+            check_cycles(&project.target, target_name, &mut Vec::new(), &mut done)?;
+        }
+
+        Ok(project)
+    }
+
+    /// Resolve this project's targets along with the targets of its workspace members, which
+    /// are all built as one project
+    fn resolve_with_members(
+        mut self,
+        project_file: &Path,
+        resolving: &mut Vec<PathBuf>,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Project, Error> {
+        self.apply_defaults();
+        let project_dir = project_file.parent().ok_or(Error::NoProjectDir)?;
+
+        let canonical_file = project_file
+            .canonicalize()
+            .map_err(|io| Error::file_io(io, project_file))?;
+        if resolving.contains(&canonical_file) {
+            let mut path = resolving
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>();
+            path.push(canonical_file.display().to_string());
+            return Err(Error::DependencyCycle { path });
+        }
+        resolving.push(canonical_file);
+
+        let unused = self.unused_keys();
+        if !unused.is_empty() {
+            warnings.push(Warning::UnusedKeys {
+                path: project_file.to_owned(),
+                keys: unused,
+            });
+        }
+
+        // the top level project was already validated by resolve, and needs between members
+        // are checked once they're all resolved
+        let version = self.project.parse_version()?;
+        if resolving.len() > 1 {
+            let errors = self.validate_targets();
+            if !errors.is_empty() {
+                return Err(Error::InvalidProject(errors));
+            }
+        }
+
+        let allowed_external = self
+            .project
+            .allow_external_paths
+            .iter()
+            .map(|allowed| {
+                project_dir.join(allowed).canonicalize().inspect_err(|_| {
+                    tracing::error!(
+                        "Could not find allowed external path {}",
+                        project_dir.join(allowed).display()
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
         let mut target = IndexMap::new();
 
         for (target_name, unresolved_target) in self.target {
+            if !unresolved_target.is_for_this_platform() {
+                warnings.push(Warning::SkippedTarget {
+                    target: target_name,
+                    platforms: unresolved_target.platforms,
+                });
+                continue;
+            }
+
             let resolved_target = unresolved_target
-                .resolve(target_name.clone(), &project_dir)
+                .resolve(
+                    target_name.clone(),
+                    project_dir,
+                    &allowed_external,
+                    warnings,
+                )
                 .inspect_err(|_| tracing::error!("Could not resolve target {}", target_name))?;
 
             target.insert(target_name, resolved_target);
         }
 
-        for resolved_target in target.values() {
-            for need in resolved_target.needs.iter() {
-                if !target.contains_key(need) {
-                    return Err(Error::NoSuchBuildTarget(need.clone()));
+        for member in self
+            .workspace
+            .iter()
+            .flat_map(|workspace| &workspace.members)
+        {
+            let member_dir = project_dir.join(member);
+            let member_file = member_dir
+                .canonicalize()
+                .map_err(|io| Error::file_io(io, &member_dir))?
+                .join(crate::PROJECT_FILENAME);
+            tracing::debug!("Using workspace member {}", member_file.display());
+
+            let member_project = UnresolvedProject::read(&member_file)?
+                .resolve_with_members(&member_file, resolving, warnings)
+                .inspect_err(|_| tracing::error!("Could not resolve member {}", member))?;
+
+            // the workspace's build directory, profiles, and default targets are used instead
+            let ignored = [
+                (
+                    "project.build_dir",
+                    member_project.project.build_dir.is_some(),
+                ),
+                ("profile", !member_project.profile.is_empty()),
+                (
+                    "project.default_targets",
+                    !member_project.project.default_targets.is_empty(),
+                ),
+            ];
+            let keys = ignored
+                .into_iter()
+                .filter(|(_, set)| *set)
+                .map(|(key, _)| key.to_string())
+                .collect::<Vec<_>>();
+            if !keys.is_empty() {
+                warnings.push(Warning::IgnoredMemberSettings {
+                    path: member_file.clone(),
+                    keys,
+                });
+            }
+
+            for (target_name, mut member_target) in member_project.target {
+                if target.contains_key(&target_name) {
+                    return Err(Error::DuplicateTarget {
+                        name: target_name,
+                        path: member_file.display().to_string(),
+                    });
                 }
+
+                // the member's environment still applies to its own targets
+                let mut env = member_project.project.env.clone();
+                env.extend(member_target.env);
+                member_target.env = env;
+
+                target.insert(target_name, member_target);
             }
         }
 
+        resolving.pop();
+
         Ok(Project {
+            file: project_file.to_owned(),
             dir: project_dir.to_owned(),
+            build_dir: project_dir.join(
+                self.project
+                    .build_dir
+                    .as_deref()
+                    .unwrap_or(crate::DEFAULT_BUILD_DIR),
+            ),
+            version,
             project: self.project,
             target,
+            profile: self.profile,
+            warnings: Vec::new(),
         })
     }
 }
 
+/// Settings for every target in the project, see UnresolvedProject::apply_defaults
+#[derive(macros::UnusedKeys, serde::Deserialize, Debug)]
+pub struct Defaults {
+    #[serde(rename = "type", deserialize_with = "one_or_many_target_type", default)]
+    pub type_: Vec<TargetType>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub include_dirs: Vec<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub cflags: Vec<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub pkg_config: Vec<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub frameworks: Vec<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub defines: Vec<String>,
+
+    pub compiler: Option<String>,
+    pub std: Option<String>,
+    pub cxx_std: Option<String>,
+    pub warnings: Option<Warnings>,
+
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+
+    #[serde(flatten)]
+    #[unused]
+    pub rest: HashMap<String, toml::Value>,
+}
+
+/// Other projects to build along with this one, whose targets can need each other
+#[derive(macros::UnusedKeys, serde::Deserialize, Debug)]
+pub struct Workspace {
+    /// Directories containing the members' project files, relative to this project
+    #[serde(deserialize_with = "one_or_many_string")]
+    pub members: Vec<String>,
+
+    #[serde(flatten)]
+    #[unused]
+    pub rest: HashMap<String, toml::Value>,
+}
+
 #[derive(macros::UnusedKeys, serde::Deserialize, Debug)]
 pub struct ProjectMeta {
     pub name: String,
     pub version: String,
+    pub description: Option<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub authors: Vec<String>,
+
+    pub build_dir: Option<String>,
+
+    /// Targets to build when none are named. Without any, every target is built.
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub default_targets: Vec<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub allow_external_paths: Vec<String>,
+
+    /// Environment variables for every compiler, archiver, and linker command
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
 
     #[serde(flatten)]
     #[unused]
     pub rest: HashMap<String, toml::Value>,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+impl ProjectMeta {
+    fn parse_version(&self) -> Result<semver::Version, Error> {
+        semver::Version::parse(&self.version).map_err(|err| Error::InvalidVersion {
+            version: self.version.clone(),
+            why: err.to_string(),
+        })
+    }
+}
+
+#[derive(macros::UnusedKeys, serde::Deserialize, Clone, Default, Debug)]
+pub struct Profile {
+    pub optimization: Option<String>,
+
+    #[serde(default)]
+    pub debug: bool,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub defines: Vec<String>,
+
+    #[serde(flatten)]
+    #[unused]
+    pub rest: HashMap<String, toml::Value>,
+}
+
+/// Whether compiler warnings fail the build
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Warnings {
+    Allow,
+    Error,
+}
+
+impl UnusedKeys for Warnings {
+    fn unused_keys(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TargetType {
     Archive,
     Dynamic,
     Binary,
+    /// Only headers, which targets needing it can include. Nothing is compiled or linked.
+    HeaderOnly,
 }
 
 impl FromStr for TargetType {
@@ -78,11 +570,23 @@ impl FromStr for TargetType {
             "archive" => Ok(TargetType::Archive),
             "dynamic" => Ok(TargetType::Dynamic),
             "binary" => Ok(TargetType::Binary),
+            "header-only" => Ok(TargetType::HeaderOnly),
             _ => Err(format!("Unknown target type {:?}", s)),
         }
     }
 }
 
+impl std::fmt::Display for TargetType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetType::Archive => write!(f, "archive"),
+            TargetType::Dynamic => write!(f, "dynamic"),
+            TargetType::Binary => write!(f, "binary"),
+            TargetType::HeaderOnly => write!(f, "header-only"),
+        }
+    }
+}
+
 impl UnusedKeys for TargetType {
     fn unused_keys(&self) -> Vec<String> {
         vec![]
@@ -148,66 +652,329 @@ bruh!(one_or_many_string, String);
 
 #[derive(macros::UnusedKeys, serde::Deserialize, Debug)]
 pub struct UnresolvedTarget {
-    #[serde(rename = "type", deserialize_with = "one_or_many_target_type")]
+    /// What to build from the sources. An archive can be built along with either a binary or a
+    /// dynamic library, but a binary and a dynamic library can't be built from the same target.
+    /// A header-only target has no sources and no other type.
+    #[serde(rename = "type", deserialize_with = "one_or_many_target_type", default)]
     pub type_: Vec<TargetType>,
 
     pub path: Option<String>,
 
-    #[serde(deserialize_with = "one_or_many_string")]
+    /// Name of the binary, instead of the target's name
+    pub output_name: Option<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
     pub sources: Vec<String>,
 
     #[serde(deserialize_with = "one_or_many_string", default)]
     pub headers: Vec<String>,
 
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub include_dirs: Vec<String>,
+
     #[serde(deserialize_with = "one_or_many_string", default)]
     pub needs: Vec<String>,
 
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub cflags: Vec<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub pkg_config: Vec<String>,
+
+    /// macOS frameworks to link, like "Cocoa"
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub frameworks: Vec<String>,
+
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub defines: Vec<String>,
+
+    /// Compiler from compilers.toml to build this target with, instead of the default
+    pub compiler: Option<String>,
+
+    /// Language standard, like "c11"
+    pub std: Option<String>,
+
+    /// Language standard for C++ sources, like "c++17". Defaults to `std`.
+    pub cxx_std: Option<String>,
+
+    /// "error" to fail the build on warnings, or "allow" even with --werror
+    pub warnings: Option<Warnings>,
+
+    /// Whether a binary should find the project's dynamic libraries where they were built
+    #[serde(default)]
+    pub rpath: bool,
+
+    /// Operating systems to build this target on, like "linux", "macos", or "windows". On
+    /// any other platform the target is left out, along with other targets' needs of it.
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub platforms: Vec<String>,
+
+    /// Shell commands to run in the target path before compiling
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub pre_build: Vec<String>,
+
+    /// Shell commands to run in the target path after linking
+    #[serde(deserialize_with = "one_or_many_string", default)]
+    pub post_build: Vec<String>,
+
+    /// Environment variables for this target's commands, on top of the project's
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+
     #[serde(flatten)]
     #[unused]
     pub rest: HashMap<String, toml::Value>,
 }
 
-impl UnresolvedTarget {
-    pub fn resolve(self, name: String, project_dir: &Path) -> Result<Target, Error> {
-        let path = if let Some(path) = self.path {
-            project_dir.join(&path).canonicalize().inspect_err(|_| {
-                tracing::error!(
-                    "Could not find target path {}",
-                    project_dir.join(path).display()
-                )
-            })?
+fn check_external_path(
+    path: PathBuf,
+    project_dir: &Path,
+    allowed_external: &[PathBuf],
+) -> Result<PathBuf, Error> {
+    if path.starts_with(project_dir)
+        || allowed_external
+            .iter()
+            .any(|allowed| path.starts_with(allowed))
+    {
+        Ok(path)
+    } else {
+        Err(Error::ExternalPath(path.display().to_string()))
+    }
+}
+
+/// Replace `$VAR` and `${VAR}` in a path with the variable's value. `$$` is a literal `$`, as is
+/// a `$` which isn't followed by a variable name.
+fn expand_env(value: &str) -> Result<String, Error> {
+    let error = |why: String| Error::EnvExpansion {
+        value: value.into(),
+        why,
+    };
+
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| error("missing } after ${".into()))?;
+            (&braced[..end], &braced[end + 1..])
+        } else if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
         } else {
-            project_dir.join(&name).canonicalize().inspect_err(|_| {
-                tracing::error!(
-                    "Could not find target path {}",
-                    project_dir.join(&name).display()
-                )
-            })?
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
         };
 
+        if name.is_empty() {
+            expanded.push('$');
+            continue;
+        }
+
+        let var = std::env::var(name).map_err(|_| error(format!("{} is not set", name)))?;
+        expanded.push_str(&var);
+        rest = after;
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// What resolve_files is looking for, so a missing file gets its own error
+#[derive(Clone, Copy)]
+enum FileKind {
+    TargetPath,
+    Source,
+    Header,
+    IncludeDir,
+}
+
+impl FileKind {
+    fn name(self) -> &'static str {
+        match self {
+            FileKind::TargetPath => "target path",
+            FileKind::Source => "source",
+            FileKind::Header => "header",
+            FileKind::IncludeDir => "include directory",
+        }
+    }
+
+    /// The error for failing to canonicalize `path`, which is only specific to this kind of file
+    /// when the file doesn't exist
+    fn not_found(self, io: std::io::Error, target: &str, path: &Path) -> Error {
+        if io.kind() != std::io::ErrorKind::NotFound {
+            return Error::file_io(io, path);
+        }
+
+        let target = target.to_owned();
+        let path = path.display().to_string();
+        match self {
+            FileKind::TargetPath => Error::TargetPathNotFound { target, path },
+            FileKind::Source => Error::SourceNotFound { target, path },
+            FileKind::Header => Error::HeaderNotFound { target, path },
+            FileKind::IncludeDir => Error::IncludeDirNotFound { target, path },
+        }
+    }
+}
+
+/// Find the files matching `patterns`. A file listed more than once, by overlapping globs or
+/// different spellings of its path, is only kept the first time with a warning, since compiling a
+/// source twice would define its symbols twice.
+fn resolve_files(
+    target_name: &str,
+    path: &Path,
+    patterns: &[String],
+    kind: FileKind,
+    project_dir: &Path,
+    allowed_external: &[PathBuf],
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let pattern = &expand_env(pattern)?;
+        if !pattern.contains(['*', '?', '[']) {
+            let file = path.join(pattern);
+            let file = file
+                .canonicalize()
+                .map_err(|io| kind.not_found(io, target_name, &file))?;
+            files.push(check_external_path(file, project_dir, allowed_external)?);
+            continue;
+        }
+
+        let full_pattern = path.join(pattern).display().to_string();
+        let matches = glob::glob(&full_pattern).map_err(|err| Error::BadGlob {
+            pattern: pattern.clone(),
+            why: err.to_string(),
+        })?;
+
+        let count = files.len();
+        for file in matches {
+            let file = file.map_err(|err| {
+                let path = err.path().to_owned();
+                Error::file_io(err.into(), path)
+            })?;
+            let file = file
+                .canonicalize()
+                .map_err(|io| Error::file_io(io, &file))?;
+            files.push(check_external_path(file, project_dir, allowed_external)?);
+        }
+
+        if files.len() == count {
+            return Err(Error::EmptyGlob(pattern.clone()));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    files.retain(|file| {
+        let first = seen.insert(file.clone());
+        if !first {
+            warnings.push(Warning::DuplicateFile {
+                target: target_name.into(),
+                what: kind.name().into(),
+                path: file.clone(),
+            });
+        }
+        first
+    });
+
+    Ok(files)
+}
+
+fn check_cycles<'t>(
+    targets: &'t IndexMap<String, Target>,
+    target_name: &'t str,
+    in_progress: &mut Vec<&'t str>,
+    done: &mut HashSet<&'t str>,
+) -> Result<(), Error> {
+    if done.contains(target_name) {
+        return Ok(());
+    }
+
+    if let Some(start) = in_progress.iter().position(|name| *name == target_name) {
+        let mut path = in_progress[start..]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        path.push(target_name.into());
+        return Err(Error::DependencyCycle { path });
+    }
+
+    in_progress.push(target_name);
+    for need in targets[target_name].needs.iter() {
+        check_cycles(targets, need, in_progress, done)?;
+    }
+    in_progress.pop();
+
+    done.insert(target_name);
+    Ok(())
+}
+
+impl UnresolvedTarget {
+    pub fn is_for_this_platform(&self) -> bool {
+        self.platforms.is_empty()
+            || self
+                .platforms
+                .iter()
+                .any(|platform| platform == std::env::consts::OS)
+    }
+
+    pub fn resolve(
+        self,
+        name: String,
+        project_dir: &Path,
+        allowed_external: &[PathBuf],
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Target, Error> {
+        let path = match self.path {
+            Some(path) => project_dir.join(expand_env(&path)?),
+            None => project_dir.join(&name),
+        };
+        let path = path
+            .canonicalize()
+            .map_err(|io| FileKind::TargetPath.not_found(io, &name, &path))?;
+
+        let mut resolve = |patterns: &[String], kind: FileKind| {
+            resolve_files(
+                &name,
+                &path,
+                patterns,
+                kind,
+                project_dir,
+                allowed_external,
+                warnings,
+            )
+        };
+        let sources = resolve(&self.sources, FileKind::Source)?;
+        let headers = resolve(&self.headers, FileKind::Header)?;
+        let include_dirs = resolve(&self.include_dirs, FileKind::IncludeDir)?;
+
         Ok(Target {
             type_: self.type_.into_iter().collect(),
             name,
-            sources: self
-                .sources
-                .into_iter()
-                .map(|source| {
-                    path.join(&source).canonicalize().inspect_err(|_| {
-                        tracing::error!("Could not find source {}", path.join(source).display())
-                    })
-                })
-                .collect::<Result<_, _>>()?,
-            headers: self
-                .headers
-                .into_iter()
-                .map(|header| {
-                    path.join(&header).canonicalize().inspect_err(|_| {
-                        tracing::error!("Could not find header {}", path.join(header).display())
-                    })
-                })
-                .collect::<Result<_, _>>()?,
+            sources,
+            headers,
+            include_dirs,
             path,
+            output_name: self.output_name,
             needs: self.needs,
+            cflags: self.cflags,
+            pkg_config: self.pkg_config,
+            frameworks: self.frameworks,
+            defines: self.defines,
+            compiler: self.compiler,
+            std: self.std,
+            cxx_std: self.cxx_std,
+            warnings: self.warnings,
+            rpath: self.rpath,
+            pre_build: self.pre_build,
+            post_build: self.post_build,
+            env: self.env,
         })
     }
 }
@@ -217,12 +984,99 @@ pub struct Target {
     pub type_: HashSet<TargetType>,
     pub name: String,
     pub path: PathBuf,
+    pub output_name: Option<String>,
     pub sources: Vec<PathBuf>,
     pub headers: Vec<PathBuf>,
+    pub include_dirs: Vec<PathBuf>,
     pub needs: Vec<String>,
+    pub cflags: Vec<String>,
+    pub pkg_config: Vec<String>,
+    pub frameworks: Vec<String>,
+    pub defines: Vec<String>,
+    pub compiler: Option<String>,
+    pub std: Option<String>,
+    pub cxx_std: Option<String>,
+    pub warnings: Option<Warnings>,
+    pub rpath: bool,
+    pub pre_build: Vec<String>,
+    pub post_build: Vec<String>,
+    pub env: IndexMap<String, String>,
+}
+
+/// Which targets need which, from Project::dependency_tree. Displays as an indented tree
+/// starting from the roots.
+#[derive(serde::Serialize, Debug)]
+pub struct DependencyTree {
+    /// Targets which nothing else needs, in the order they're declared
+    pub roots: Vec<String>,
+    pub targets: IndexMap<String, TargetDependencies>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct TargetDependencies {
+    /// The target's own needs
+    pub direct: Vec<String>,
+    /// Everything the target needs directly or through other targets, in the order they're
+    /// first reached
+    pub transitive: Vec<String>,
+    /// Whether the target ends up needing itself. Resolving a project rejects cycles, but the
+    /// tree can still describe them.
+    pub in_cycle: bool,
+}
+
+impl DependencyTree {
+    fn write_tree<'my>(
+        &'my self,
+        f: &mut std::fmt::Formatter<'_>,
+        target_name: &'my str,
+        depth: usize,
+        shown: &mut HashSet<&'my str>,
+    ) -> std::fmt::Result {
+        write!(f, "{}{}", "    ".repeat(depth), target_name)?;
+
+        // anything already shown (or still being shown, for a cycle) isn't expanded again
+        if !shown.insert(target_name) {
+            return writeln!(f, " (*)");
+        }
+        writeln!(f)?;
+
+        if let Some(target) = self.targets.get(target_name) {
+            for need in target.direct.iter() {
+                self.write_tree(f, need, depth + 1, shown)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for DependencyTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut shown = HashSet::new();
+        for root in self.roots.iter() {
+            self.write_tree(f, root, 0, &mut shown)?;
+        }
+        Ok(())
+    }
 }
 
 impl Project {
+    /// Look up a profile by name. Without a name, the project's default profile is used if it
+    /// has one.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, Error> {
+        match name {
+            Some(name) => self
+                .profile
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::NoSuchProfile(name.into())),
+            None => Ok(self
+                .profile
+                .get(crate::DEFAULT_PROFILE)
+                .cloned()
+                .unwrap_or_default()),
+        }
+    }
+
     fn unique_targets_in_order_from<'my>(
         &'my self,
         target_name: &'my str,
@@ -235,17 +1089,13 @@ impl Project {
 
         let mut targets = Vec::new();
 
+        // resolve already rejected cycles, so this always ends
         for needs in target.needs.iter() {
-            if built.insert(needs.as_str()) {
-                tracing::trace!("Will build {}: needed by {}", needs, target_name);
-                targets.push((
-                    needs.as_str(),
-                    self.target
-                        .get(needs)
-                        .ok_or_else(|| Error::NoSuchBuildTarget(needs.into()))?,
-                ));
-            } else {
+            if built.contains(needs.as_str()) {
                 tracing::trace!("Already building {}, needed by {}", needs, target_name);
+            } else {
+                tracing::trace!("Will build {}: needed by {}", needs, target_name);
+                targets.extend(self.unique_targets_in_order_from(needs, built)?);
             }
         }
 
@@ -259,6 +1109,8 @@ impl Project {
         Ok(targets)
     }
 
+    /// The named targets and everything they need, each after the targets it needs. Needs come
+    /// in the order a target lists them, so the order is the same every run.
     pub fn targets_in_order_from<'my>(
         &'my self,
         target_names: impl Iterator<Item = &'my str>,
@@ -271,7 +1123,215 @@ impl Project {
         Ok(targets)
     }
 
+    /// The targets to build when none are named: the project's default_targets, or every target
+    fn default_target_names(&self) -> Box<dyn Iterator<Item = &str> + '_> {
+        if self.project.default_targets.is_empty() {
+            Box::new(self.target.keys().map(|name| name.as_str()))
+        } else {
+            Box::new(
+                self.project
+                    .default_targets
+                    .iter()
+                    .map(|name| name.as_str()),
+            )
+        }
+    }
+
     pub fn targets_in_order(&self) -> Result<Vec<(&str, &Target)>, Error> {
-        self.targets_in_order_from(self.target.keys().map(|name| name.as_str()))
+        self.targets_in_order_from(self.default_target_names())
+    }
+
+    /// Group the named targets and everything they need into levels. Each target only needs
+    /// targets from earlier levels, so the targets in one level can be built at the same time.
+    /// Within a level, targets are in the order they're declared in the project file, so the
+    /// order is the same every run.
+    pub fn target_levels_from<'my>(
+        &'my self,
+        target_names: impl Iterator<Item = &'my str>,
+    ) -> Result<Vec<Vec<(&'my str, &'my Target)>>, Error> {
+        let mut wanted = HashSet::new();
+        let mut stack = target_names.collect::<Vec<_>>();
+        while let Some(target_name) = stack.pop() {
+            let target = self
+                .target
+                .get(target_name)
+                .ok_or_else(|| Error::NoSuchBuildTarget(target_name.into()))?;
+            if wanted.insert(target_name) {
+                stack.extend(target.needs.iter().map(|need| need.as_str()));
+            }
+        }
+
+        let mut in_degree = HashMap::<&str, usize>::new();
+        let mut dependents = HashMap::<&str, Vec<&str>>::new();
+        for (target_name, target) in self.target.iter() {
+            if !wanted.contains(target_name.as_str()) {
+                continue;
+            }
+
+            in_degree.insert(target_name, target.needs.len());
+            for need in target.needs.iter() {
+                dependents.entry(need).or_default().push(target_name);
+            }
+        }
+
+        let mut levels = Vec::new();
+        let mut level = self
+            .target
+            .keys()
+            .map(|name| name.as_str())
+            .filter(|name| in_degree.get(name) == Some(&0))
+            .collect::<Vec<_>>();
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            for target_name in level.iter() {
+                in_degree.remove(target_name);
+                for dependent in dependents.get(target_name).into_iter().flatten() {
+                    let degree = in_degree
+                        .get_mut(dependent)
+                        .ok_or_else(|| Error::Bug("Dependent was already built".into()))?;
+                    *degree -= 1;
+                    if *degree == 0 {
+                        next_level.push(*dependent);
+                    }
+                }
+            }
+
+            next_level.sort_by_key(|name| self.target.get_index_of(*name));
+            tracing::trace!("Will build {:?} together", level);
+            levels.push(
+                level
+                    .into_iter()
+                    .map(|name| (name, &self.target[name]))
+                    .collect(),
+            );
+            level = next_level;
+        }
+
+        if !in_degree.is_empty() {
+            let mut cycle = in_degree.into_keys().map(String::from).collect::<Vec<_>>();
+            cycle.sort();
+            return Err(Error::DependencyCycle { path: cycle });
+        }
+
+        Ok(levels)
+    }
+
+    /// Find each target's direct and transitive needs, and the targets nothing else needs
+    pub fn dependency_tree(&self) -> DependencyTree {
+        let needed = self
+            .target
+            .values()
+            .flat_map(|target| target.needs.iter().map(|need| need.as_str()))
+            .collect::<HashSet<_>>();
+        let roots = self
+            .target
+            .keys()
+            .filter(|name| !needed.contains(name.as_str()))
+            .cloned()
+            .collect();
+
+        let mut targets = IndexMap::new();
+        for (target_name, target) in self.target.iter() {
+            // visit everything reachable once, so a cycle can't recurse forever
+            let mut transitive = Vec::<String>::new();
+            let mut stack = target.needs.iter().rev().collect::<Vec<_>>();
+            while let Some(need) = stack.pop() {
+                if transitive.contains(need) {
+                    continue;
+                }
+                transitive.push(need.clone());
+                if let Some(need) = self.target.get(need) {
+                    stack.extend(need.needs.iter().rev());
+                }
+            }
+
+            targets.insert(
+                target_name.clone(),
+                TargetDependencies {
+                    direct: target.needs.clone(),
+                    in_cycle: transitive.contains(target_name),
+                    transitive,
+                },
+            );
+        }
+
+        DependencyTree { roots, targets }
+    }
+
+    /// Find the targets which need to be rebuilt after `changed` files changed: the targets
+    /// which list them as sources or headers, and everything that needs those targets. A change
+    /// to the project file affects every target.
+    pub fn targets_affected_by(&self, changed: &HashSet<PathBuf>) -> Vec<&str> {
+        if changed.contains(&self.file) {
+            return self.target.keys().map(|name| name.as_str()).collect();
+        }
+
+        let mut affected = self
+            .target
+            .values()
+            .filter(|target| {
+                target
+                    .sources
+                    .iter()
+                    .chain(target.headers.iter())
+                    .any(|file| changed.contains(file))
+            })
+            .map(|target| target.name.as_str())
+            .collect::<HashSet<_>>();
+
+        loop {
+            let dependents = self
+                .target
+                .values()
+                .filter(|target| !affected.contains(target.name.as_str()))
+                .filter(|target| {
+                    target
+                        .needs
+                        .iter()
+                        .any(|need| affected.contains(need.as_str()))
+                })
+                .map(|target| target.name.as_str())
+                .collect::<Vec<_>>();
+            if dependents.is_empty() {
+                break;
+            }
+            affected.extend(dependents);
+        }
+
+        self.target
+            .keys()
+            .map(|name| name.as_str())
+            .filter(|name| affected.contains(name))
+            .collect()
+    }
+
+    /// Describe the dependency graph between targets in Graphviz DOT format
+    pub fn dependency_graph(&self) -> String {
+        let mut dot = format!("digraph {:?} {{\n", self.project.name);
+        for (target_name, target) in self.target.iter() {
+            let shape = if target.type_.contains(&TargetType::Binary) {
+                "box"
+            } else if target.type_.contains(&TargetType::Dynamic) {
+                "ellipse"
+            } else if target.type_.contains(&TargetType::HeaderOnly) {
+                "note"
+            } else {
+                "folder"
+            };
+            dot.push_str(&format!("    {:?} [shape={}];\n", target_name, shape));
+        }
+
+        for (target_name, target) in self.target.iter() {
+            for need in target.needs.iter() {
+                dot.push_str(&format!("    {:?} -> {:?};\n", target_name, need));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn target_levels(&self) -> Result<Vec<Vec<(&str, &Target)>>, Error> {
+        self.target_levels_from(self.default_target_names())
     }
 }
@@ -0,0 +1,165 @@
+mod common;
+
+use common::TestProject;
+use std::process::{Command, Output};
+
+const PROJECT: &str = r#"
+    [project]
+    name = "cli"
+    version = "1.0.0"
+
+    [target.app]
+    type = "binary"
+    path = "."
+    sources = ["main.c"]
+"#;
+
+fn cr(project: &TestProject, args: &[&str]) -> Output {
+    cr_command(project).args(args).output().unwrap()
+}
+
+fn cr_command(project: &TestProject) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_cretaceous"));
+    command.arg("-C").arg(&project.dir);
+    command
+}
+
+#[test]
+fn json_errors_name_the_missing_target() {
+    let project = TestProject::new("json-no-such-target", PROJECT, &["main.c"]);
+    let output = cr(&project, &["--error-format", "json", "build", "-t", "nope"]);
+
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error: serde_json::Value = serde_json::from_str(stderr.lines().last().unwrap()).unwrap();
+    assert_eq!(error["kind"], "NoSuchBuildTarget");
+    assert!(error["message"].as_str().unwrap().contains("nope"));
+}
+
+#[test]
+fn bad_format_names_the_option() {
+    let project = TestProject::new("bad-format", PROJECT, &["main.c"]);
+
+    let output = cr(&project, &["build", "--format", "xml"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Unknown --format \"xml\""), "{}", stderr);
+
+    let output = cr(&project, &["--error-format", "xml", "build"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Unknown --error-format \"xml\""),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn run_finds_a_renamed_binary() {
+    let project = TestProject::new(
+        "run-renamed",
+        r#"
+        [project]
+        name = "renamed"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        path = "."
+        sources = ["main.c"]
+        output_name = "myprogram"
+        "#,
+        &["main.c"],
+    );
+    std::fs::write(
+        project.dir.join("main.c"),
+        "#include <stdio.h>\nint main(int argc, char **argv) { printf(\"ran %s\\n\", argv[1]); }\n",
+    )
+    .unwrap();
+
+    let output = cr(&project, &["run", "app", "renamed"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "ran renamed\n");
+    assert!(project
+        .dir
+        .join(format!("myprogram{}", std::env::consts::EXE_SUFFIX))
+        .exists());
+    assert!(!project
+        .dir
+        .join(format!("app{}", std::env::consts::EXE_SUFFIX))
+        .exists());
+}
+
+#[test]
+fn response_files_are_in_the_build_directory() {
+    let project = TestProject::new(
+        "response-files",
+        r#"
+        [project]
+        name = "response"
+        version = "1.0.0"
+
+        [target.lib]
+        type = "archive"
+        sources = ["lib.c"]
+
+        [target.app]
+        type = "binary"
+        needs = "lib"
+        sources = ["main.c"]
+        "#,
+        &["lib/lib.c", "app/main.c"],
+    );
+    std::fs::write(project.dir.join("app/main.c"), "int main() {}\n").unwrap();
+    let response_files = [
+        project.dir.join("build/lib.archive.rsp"),
+        project.dir.join("build/app.link.rsp"),
+    ];
+
+    let output = cr_command(&project)
+        .arg("build")
+        .env("CR_RESPONSE_FILE_THRESHOLD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    for response_file in response_files.iter() {
+        assert!(response_file.exists(), "{}", response_file.display());
+    }
+    assert!(!project.dir.join("lib/lib.archive.rsp").exists());
+    assert!(!project.dir.join("app/app.link.rsp").exists());
+
+    let output = cr(&project, &["clean"]);
+    assert!(output.status.success(), "{:?}", output);
+    for response_file in response_files.iter() {
+        assert!(!response_file.exists(), "{}", response_file.display());
+    }
+}
+
+#[test]
+fn response_file_leaves_the_wrapped_linker_on_the_command_line() {
+    let project = TestProject::new("response-file-wrapper", PROJECT, &["main.c"]);
+    std::fs::write(project.dir.join("main.c"), "int main() {}\n").unwrap();
+
+    // env runs the rest of its arguments, so it works as a wrapper which needs the linker
+    let output = cr_command(&project)
+        .arg("build")
+        .env("CR_RESPONSE_FILE_THRESHOLD", "1")
+        .env("CR_LINKER_WRAPPER", "env")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let response_file = project.dir.join("build/app.link.rsp");
+    assert!(
+        stderr.contains(&format!(
+            "[\"env\", \"gcc\", \"@{}\"]",
+            response_file.display()
+        )),
+        "{}",
+        stderr
+    );
+    let contents = std::fs::read_to_string(response_file).unwrap();
+    assert!(!contents.lines().any(|line| line == "env" || line == "gcc"));
+}
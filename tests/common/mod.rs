@@ -0,0 +1,100 @@
+//! Small projects written to the system temp directory, for testing resolution and the commands
+//! a build would run without running them
+
+#![allow(dead_code)]
+
+use cretaceous::{compiler::TargetPlan, error::Error, project::Project, BuildOptions};
+use std::{path::PathBuf, process::Command};
+
+/// A project in its own directory, which is removed when this is dropped
+pub struct TestProject {
+    pub dir: PathBuf,
+}
+
+impl TestProject {
+    /// Write `project` to C.toml in a new directory along with `files`, which are created
+    /// empty. `name` must be unique among the tests, since they run at the same time.
+    pub fn new(name: &str, project: &str, files: &[&str]) -> TestProject {
+        let dir = std::env::temp_dir().join(format!("cr-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        std::fs::write(dir.join(cretaceous::PROJECT_FILENAME), project).unwrap();
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        TestProject { dir }
+    }
+
+    pub fn file(&self) -> PathBuf {
+        self.dir.join(cretaceous::PROJECT_FILENAME)
+    }
+
+    pub fn load(&self) -> Result<Project, Error> {
+        cretaceous::load_project(&self.file())
+    }
+}
+
+impl Drop for TestProject {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Load one of the example projects in eg/
+pub fn load_example(path: &str) -> Result<Project, Error> {
+    cretaceous::load_project(
+        &std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("eg")
+            .join(path)
+            .join(cretaceous::PROJECT_FILENAME),
+    )
+}
+
+/// What building the default targets of `project` would do
+pub fn plan(project: &Project, options: &BuildOptions) -> Vec<TargetPlan> {
+    let compilers = options.compilers(project).unwrap();
+    let profile = options.profile(project).unwrap();
+    let targets = options
+        .target_levels(project)
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    compilers
+        .build_plan(project, &targets, &profile, options)
+        .unwrap()
+}
+
+/// The plan for one target
+pub fn target_plan<'p>(plan: &'p [TargetPlan], name: &str) -> &'p TargetPlan {
+    plan.iter()
+        .find(|target| target.name == name)
+        .unwrap_or_else(|| panic!("no plan for {}", name))
+}
+
+/// What building `project` would do according to `cr build --dry-run --format json`, run in a
+/// child process with `env` set. Tests which need environment variables use this, since setting
+/// them in the test process would race with the other tests reading them.
+pub fn plan_with_env(project: &TestProject, env: &[(&str, &str)]) -> Vec<serde_json::Value> {
+    let output = Command::new(env!("CARGO_BIN_EXE_cretaceous"))
+        .arg("-C")
+        .arg(&project.dir)
+        .args(["build", "--dry-run", "--format", "json"])
+        .envs(env.iter().copied())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// The plan for one target from plan_with_env
+pub fn json_target_plan<'p>(plan: &'p [serde_json::Value], name: &str) -> &'p serde_json::Value {
+    plan.iter()
+        .find(|target| target["name"] == name)
+        .unwrap_or_else(|| panic!("no plan for {}", name))
+}
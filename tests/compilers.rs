@@ -0,0 +1,20 @@
+use cretaceous::{DEFAULT_COMPILER, DEFAULT_COMPILERS};
+
+#[test]
+fn shipped_compilers_have_the_default() {
+    let compilers: toml::Table = toml::from_str(DEFAULT_COMPILERS).unwrap();
+    assert!(
+        compilers.contains_key(DEFAULT_COMPILER),
+        "no {} in dist/compilers.toml",
+        DEFAULT_COMPILER
+    );
+}
+
+// with the dev feature, the config directory is dist/
+#[test]
+#[cfg(feature = "dev")]
+fn default_compiler_resolves() {
+    let compiler = cretaceous::default_compiler().unwrap();
+    assert_eq!(compiler.name, DEFAULT_COMPILER);
+    assert!(!compiler.inner.compile_command.is_empty());
+}
@@ -0,0 +1,755 @@
+mod common;
+
+use common::{json_target_plan, load_example, plan, plan_with_env, target_plan, TestProject};
+use cretaceous::{project::Warning, BuildOptions};
+
+#[test]
+fn archive_command_is_not_repeated() {
+    let project = TestProject::new(
+        "archive-argv",
+        r#"
+        [project]
+        name = "archive"
+        version = "1.0.0"
+
+        [target.lib]
+        type = "archive"
+        sources = ["lib.c"]
+        "#,
+        &["lib/lib.c"],
+    );
+    let project = project.load().unwrap();
+    let plan = plan(&project, &Default::default());
+
+    let archive = target_plan(&plan, "lib").archive.as_ref().unwrap();
+    assert_ne!(archive[0], archive[1]);
+    assert_eq!(
+        archive.iter().filter(|arg| **arg == archive[0]).count(),
+        1,
+        "{:?}",
+        archive
+    );
+}
+
+#[test]
+fn define_with_value_is_one_argument() {
+    let project = TestProject::new(
+        "define-value",
+        r#"
+        [project]
+        name = "define"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        path = "."
+        sources = ["main.c"]
+        defines = ["NDEBUG", 'VERSION="1.2 beta"']
+        "#,
+        &["main.c"],
+    );
+    let project = project.load().unwrap();
+    let plan = plan(&project, &Default::default());
+
+    let compile = &target_plan(&plan, "app").compile[0];
+    let version = compile
+        .iter()
+        .position(|arg| arg == r#"VERSION="1.2 beta""#)
+        .unwrap();
+    assert_eq!(compile[version - 1], "-D");
+    assert!(compile.windows(2).any(|args| args == ["-D", "NDEBUG"]));
+}
+
+#[test]
+fn paths_with_spaces_stay_whole() {
+    let project = TestProject::new(
+        "path-spaces",
+        r#"
+        [project]
+        name = "spaces"
+        version = "1.0.0"
+
+        [target.lib]
+        type = "archive"
+        path = "my lib"
+        sources = ["lib.c"]
+
+        [target.app]
+        type = "binary"
+        path = "my app"
+        needs = "lib"
+        sources = ["main.c"]
+        "#,
+        &["my lib/lib.c", "my app/main.c"],
+    );
+    let dir = project.dir.clone();
+    let project = project.load().unwrap();
+    let plan = plan(&project, &Default::default());
+
+    let object = |path: &str| dir.join("build").join(path).display().to_string();
+
+    let archive = target_plan(&plan, "lib").archive.as_ref().unwrap();
+    assert!(archive.contains(&dir.join("my lib/liblib.a").display().to_string()));
+    assert!(archive.contains(&object("my lib/lib.o")));
+
+    let link = target_plan(&plan, "app").binary_link.as_ref().unwrap();
+    assert!(link.contains(&object("my app/main.o")));
+    assert!(link.ends_with(&["-o".into(), dir.join("my app/app").display().to_string()]));
+}
+
+/// The file names of a dynamic library and of a binary which needs it, and the binary's link
+/// command
+fn dynamic_and_binary_names(name: &str) -> (String, String, Vec<String>) {
+    let project = TestProject::new(
+        name,
+        r#"
+        [project]
+        name = "naming"
+        version = "1.0.0"
+
+        [target.lib]
+        type = "dynamic"
+        sources = ["lib.c"]
+
+        [target.app]
+        type = "binary"
+        needs = "lib"
+        sources = ["main.c"]
+        "#,
+        &["lib/lib.c", "app/main.c"],
+    );
+    let project = project.load().unwrap();
+    let plan = plan(&project, &Default::default());
+
+    let file_name = |command: &Option<Vec<String>>| {
+        let output = command.as_ref().unwrap().last().unwrap();
+        std::path::Path::new(output)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    };
+    let app = target_plan(&plan, "app");
+    (
+        file_name(&target_plan(&plan, "lib").dynamic_link),
+        file_name(&app.binary_link),
+        app.binary_link.clone().unwrap(),
+    )
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn linux_output_names() {
+    let (dynamic, binary, link) = dynamic_and_binary_names("naming-linux");
+    assert_eq!(dynamic, "liblib.so");
+    assert_eq!(binary, "app");
+    assert!(link.contains(&"-llib".into()));
+}
+
+#[test]
+#[cfg(target_os = "macos")]
+fn macos_output_names() {
+    let (dynamic, binary, link) = dynamic_and_binary_names("naming-macos");
+    assert_eq!(dynamic, "liblib.dylib");
+    assert_eq!(binary, "app");
+    assert!(link.contains(&"-llib".into()));
+}
+
+#[test]
+#[cfg(target_os = "windows")]
+fn windows_output_names() {
+    let (dynamic, binary, _) = dynamic_and_binary_names("naming-windows");
+    assert_eq!(dynamic, "lib.dll");
+    assert_eq!(binary, "app.exe");
+}
+
+#[test]
+fn archive_with_another_name_is_linked_by_path() {
+    let project = TestProject::new(
+        "renamed-archive",
+        r#"
+        [project]
+        name = "renamed"
+        version = "1.0.0"
+
+        [target.renamed]
+        type = "archive"
+        sources = ["lib.c"]
+
+        [target.app]
+        type = "binary"
+        needs = "renamed"
+        sources = ["main.c"]
+        "#,
+        &["renamed/lib.c", "app/main.c"],
+    );
+
+    let plan = plan_with_env(
+        &project,
+        &[("CR_ARCHIVE_RENAMED_OUTPUT_FORMAT", "%target-static.a")],
+    );
+    let target = |name| json_target_plan(&plan, name);
+
+    let archive = project
+        .dir
+        .join("renamed/renamed-static.a")
+        .display()
+        .to_string();
+    let link = target("app")["binary_link"].as_array().unwrap();
+    assert!(link.contains(&archive.as_str().into()), "{:?}", link);
+    assert!(
+        !link
+            .iter()
+            .any(|arg| arg.as_str().unwrap().ends_with("lrenamed")),
+        "{:?}",
+        link
+    );
+    assert!(target("renamed")["archive"]
+        .as_array()
+        .unwrap()
+        .contains(&archive.as_str().into()));
+}
+
+#[test]
+fn c_and_cxx_sources_get_their_own_std() {
+    let project = TestProject::new(
+        "std",
+        r#"
+        [project]
+        name = "std"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        path = "."
+        sources = ["main.c", "util.cpp"]
+        std = "c11"
+        cxx_std = "c++17"
+
+        [target.plain]
+        type = "archive"
+        sources = ["plain.c"]
+        "#,
+        &["main.c", "util.cpp", "plain/plain.c"],
+    );
+    let project = project.load().unwrap();
+    let plan = plan(&project, &Default::default());
+
+    let app = target_plan(&plan, "app");
+    let compile = |source: &str| {
+        app.compile
+            .iter()
+            .find(|command| command.iter().any(|arg| arg.ends_with(source)))
+            .unwrap()
+    };
+    assert!(compile("main.c").contains(&"-std=c11".into()));
+    assert!(compile("util.cpp").contains(&"-std=c++17".into()));
+    assert!(!target_plan(&plan, "plain").compile[0]
+        .iter()
+        .any(|arg| arg.starts_with("-std=")));
+}
+
+#[test]
+fn sanitizers_are_compiled_and_linked_with() {
+    let project = TestProject::new(
+        "sanitize",
+        r#"
+        [project]
+        name = "sanitize"
+        version = "1.0.0"
+
+        [target.lib]
+        type = "dynamic"
+        sources = ["lib.c"]
+
+        [target.app]
+        type = "binary"
+        needs = "lib"
+        sources = ["main.c"]
+        "#,
+        &["lib/lib.c", "app/main.c"],
+    );
+    let project = project.load().unwrap();
+    let sanitize = "-fsanitize=address,undefined".to_string();
+
+    let options = BuildOptions {
+        sanitize: vec!["address".into(), "undefined".into()],
+        ..Default::default()
+    };
+    let sanitized = plan(&project, &options);
+    let lib = target_plan(&sanitized, "lib");
+    let app = target_plan(&sanitized, "app");
+    assert!(lib.compile[0].contains(&sanitize));
+    assert!(lib.dynamic_link.as_ref().unwrap().contains(&sanitize));
+    assert!(app.compile[0].contains(&sanitize));
+    assert!(app.binary_link.as_ref().unwrap().contains(&sanitize));
+
+    let unsanitized = plan(&project, &Default::default());
+    for target in unsanitized.iter() {
+        let commands = target
+            .compile
+            .iter()
+            .chain(target.dynamic_link.iter())
+            .chain(target.binary_link.iter());
+        for command in commands {
+            assert!(!command.iter().any(|arg| arg.starts_with("-fsanitize")));
+        }
+    }
+}
+
+#[test]
+fn werror_only_when_enabled() {
+    let project = TestProject::new(
+        "werror",
+        r#"
+        [project]
+        name = "werror"
+        version = "1.0.0"
+
+        [target.default]
+        type = "archive"
+        sources = ["default.c"]
+
+        [target.strict]
+        type = "archive"
+        sources = ["strict.c"]
+        warnings = "error"
+
+        [target.lenient]
+        type = "archive"
+        sources = ["lenient.c"]
+        warnings = "allow"
+        "#,
+        &["default/default.c", "strict/strict.c", "lenient/lenient.c"],
+    );
+    let project = project.load().unwrap();
+    let werror = |plan: &[cretaceous::compiler::TargetPlan], name: &str| {
+        target_plan(plan, name).compile[0].contains(&"-Werror".into())
+    };
+
+    let without = plan(&project, &Default::default());
+    assert!(!werror(&without, "default"));
+    assert!(werror(&without, "strict"));
+    assert!(!werror(&without, "lenient"));
+
+    let options = BuildOptions {
+        werror: true,
+        ..Default::default()
+    };
+    let with = plan(&project, &options);
+    assert!(werror(&with, "default"));
+    assert!(werror(&with, "strict"));
+    assert!(!werror(&with, "lenient"));
+}
+
+#[test]
+fn diamond_needs_have_each_path_once() {
+    // every library is in the same directory, so each of them and the binary would get it once
+    // per need without deduplication
+    let project = TestProject::new(
+        "diamond",
+        r#"
+        [project]
+        name = "diamond"
+        version = "1.0.0"
+
+        [target.base]
+        type = "dynamic"
+        path = "libs"
+        sources = ["base.c"]
+
+        [target.left]
+        type = "dynamic"
+        path = "libs"
+        needs = "base"
+        sources = ["left.c"]
+
+        [target.right]
+        type = "dynamic"
+        path = "libs"
+        needs = "base"
+        sources = ["right.c"]
+
+        [target.top]
+        type = "binary"
+        path = "."
+        needs = ["left", "right"]
+        sources = ["top.c"]
+        "#,
+        &["libs/base.c", "libs/left.c", "libs/right.c", "top.c"],
+    );
+    let libs = project.dir.join("libs").display().to_string();
+    let project = project.load().unwrap();
+    let plan = plan(&project, &Default::default());
+
+    let count = |command: &[String], arg: &str| command.iter().filter(|a| *a == arg).count();
+    for name in ["left", "right", "top"] {
+        let target = target_plan(&plan, name);
+        assert_eq!(count(&target.compile[0], &libs), 1, "{:?}", target.compile);
+    }
+    let link_path = format!("-L{}", libs);
+    let link = target_plan(&plan, "top").binary_link.as_ref().unwrap();
+    assert_eq!(count(link, &link_path), 1, "{:?}", link);
+    for name in ["left", "right"] {
+        let link = target_plan(&plan, name).dynamic_link.as_ref().unwrap();
+        assert_eq!(count(link, &link_path), 1, "{:?}", link);
+    }
+}
+
+#[test]
+fn rpath_once_per_dynamic_need() {
+    let project = TestProject::new(
+        "rpath",
+        r#"
+        [project]
+        name = "rpath"
+        version = "1.0.0"
+
+        [target.one]
+        type = "dynamic"
+        sources = ["one.c"]
+
+        [target.two]
+        type = ["dynamic", "archive"]
+        sources = ["two.c"]
+
+        [target.static]
+        type = "archive"
+        sources = ["static.c"]
+
+        [target.app]
+        type = "binary"
+        needs = ["one", "two", "static"]
+        sources = ["main.c"]
+        rpath = true
+
+        [target.plain]
+        type = "binary"
+        needs = ["one", "two", "static"]
+        sources = ["main.c"]
+        "#,
+        &[
+            "one/one.c",
+            "two/two.c",
+            "static/static.c",
+            "app/main.c",
+            "plain/main.c",
+        ],
+    );
+    let dir = project.dir.clone();
+    let project = project.load().unwrap();
+    let plan = plan(&project, &Default::default());
+
+    let rpaths = |name: &str| {
+        target_plan(&plan, name)
+            .binary_link
+            .as_ref()
+            .unwrap()
+            .iter()
+            .filter(|arg| arg.starts_with("-Wl,-rpath,"))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        rpaths("app"),
+        ["one", "two"].map(|need| format!("-Wl,-rpath,{}", dir.join(need).display()))
+    );
+    assert!(rpaths("plain").is_empty());
+}
+
+#[test]
+fn msvc_commands() {
+    let project = TestProject::new(
+        "msvc",
+        r#"
+        [project]
+        name = "msvc"
+        version = "1.0.0"
+
+        [target.grow]
+        type = "archive"
+        sources = ["seed.c"]
+
+        [target.soil]
+        type = "dynamic"
+        sources = ["soil.c"]
+
+        [target.tomato]
+        type = "dynamic"
+        needs = "soil"
+        sources = ["tomato.c"]
+
+        [target.app]
+        type = "binary"
+        needs = ["grow", "tomato"]
+        sources = ["main.c"]
+        "#,
+        &[
+            "grow/seed.c",
+            "soil/soil.c",
+            "tomato/tomato.c",
+            "app/main.c",
+        ],
+    );
+    let dir = project.dir.clone();
+    let project = project.load().unwrap();
+    let options = BuildOptions {
+        compiler: Some("msvc".into()),
+        ..Default::default()
+    };
+    let plan = plan(&project, &options);
+
+    let path = |path: &str| dir.join(path).display().to_string();
+    let fused = |option: &str, path: &str| format!("{}{}", option, dir.join(path).display());
+
+    assert_eq!(
+        target_plan(&plan, "grow").compile,
+        [vec![
+            "cl".into(),
+            "/nologo".into(),
+            "/c".into(),
+            "/I".into(),
+            path("grow"),
+            path("grow/seed.c"),
+            fused("/Fo", "build/grow/seed.obj"),
+        ]]
+    );
+    assert_eq!(
+        target_plan(&plan, "grow").archive.as_ref().unwrap(),
+        &[
+            "lib".into(),
+            "/nologo".into(),
+            fused("/OUT:", "grow/grow.lib"),
+            path("build/grow/seed.obj"),
+        ]
+    );
+    assert_eq!(
+        target_plan(&plan, "tomato").dynamic_link.as_ref().unwrap(),
+        &[
+            "cl".into(),
+            "/nologo".into(),
+            "/LD".into(),
+            path("build/tomato/tomato.obj"),
+            "soil.lib".into(),
+            fused("/Fe", "tomato/tomato.dll"),
+            "/link".into(),
+            fused("/LIBPATH:", "tomato"),
+            fused("/LIBPATH:", "soil"),
+        ]
+    );
+    assert_eq!(
+        target_plan(&plan, "app").binary_link.as_ref().unwrap(),
+        &[
+            "cl".into(),
+            "/nologo".into(),
+            path("build/app/main.obj"),
+            path("grow/grow.lib"),
+            "tomato.lib".into(),
+            format!(
+                "{}{}",
+                fused("/Fe", "app/app"),
+                std::env::consts::EXE_SUFFIX
+            ),
+            "/link".into(),
+            fused("/LIBPATH:", "app"),
+            fused("/LIBPATH:", "grow"),
+            fused("/LIBPATH:", "tomato"),
+        ]
+    );
+}
+
+#[test]
+fn archive_finds_objects_with_another_extension() {
+    let project = TestProject::new(
+        "obj-objects",
+        r#"
+        [project]
+        name = "obj"
+        version = "1.0.0"
+
+        [target.lib]
+        type = "archive"
+        sources = ["one.c", "nested/two.c"]
+        "#,
+        &["lib/one.c", "lib/nested/two.c"],
+    );
+
+    let plan = plan_with_env(
+        &project,
+        &[("CR_COMPILER_OUTPUT_FORMAT", "%source_basename.obj")],
+    );
+    let lib = json_target_plan(&plan, "lib");
+
+    let objects = ["build/lib/one.obj", "build/lib/nested/two.obj"]
+        .map(|object| project.dir.join(object).display().to_string());
+    let compiled = lib["compile"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|command| {
+            command
+                .as_array()
+                .unwrap()
+                .last()
+                .unwrap()
+                .as_str()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(compiled, objects);
+
+    let archive = lib["archive"].as_array().unwrap();
+    let archived = archive
+        .iter()
+        .map(|arg| arg.as_str().unwrap())
+        .filter(|arg| arg.ends_with(".obj") || arg.ends_with(".o"))
+        .collect::<Vec<_>>();
+    assert_eq!(archived, objects);
+}
+
+#[test]
+fn script_runs_build_commands_around_each_target() {
+    let project = TestProject::new(
+        "script",
+        r#"
+        [project]
+        name = "script"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        sources = ["main.c"]
+        pre_build = ["echo %target > pre.txt"]
+        post_build = ["test -f %output", "echo done > post.txt"]
+        "#,
+        &["app/main.c"],
+    );
+    std::fs::write(project.dir.join("app/main.c"), "int main() {}\n").unwrap();
+    let app_dir = project.dir.join("app");
+    let loaded = project.load().unwrap();
+    let script = cretaceous::compiler::shell_script(&plan(&loaded, &Default::default()));
+
+    let lines = script.lines().collect::<Vec<_>>();
+    let cd = format!("(cd {} && ", app_dir.display());
+    let position = |line: &str| lines.iter().position(|l| *l == line).unwrap();
+    let pre = position(&format!("{}echo app > pre.txt)", cd));
+    let test = position(&format!("{}test -f {})", cd, app_dir.join("app").display()));
+    let post = position(&format!("{}echo done > post.txt)", cd));
+    let compile = lines.iter().position(|l| l.contains(" -c ")).unwrap();
+    let link = lines.iter().rposition(|l| l.starts_with("gcc ")).unwrap();
+    assert!(pre < compile && compile < link && link < test && test < post);
+
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(&script)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        std::fs::read_to_string(app_dir.join("pre.txt")).unwrap(),
+        "app\n"
+    );
+    assert!(app_dir.join("post.txt").exists());
+}
+
+/// The binary and dynamic link commands of targets which link the Cocoa framework
+fn framework_links(name: &str) -> (Vec<String>, Vec<String>) {
+    let project = TestProject::new(
+        name,
+        r#"
+        [project]
+        name = "frameworks"
+        version = "1.0.0"
+
+        [target.lib]
+        type = "dynamic"
+        sources = ["lib.c"]
+        frameworks = ["Cocoa"]
+
+        [target.app]
+        type = "binary"
+        sources = ["main.c"]
+        frameworks = ["Cocoa"]
+        "#,
+        &["lib/lib.c", "app/main.c"],
+    );
+    let project = project.load().unwrap();
+    let plan = plan(&project, &Default::default());
+    (
+        target_plan(&plan, "app").binary_link.clone().unwrap(),
+        target_plan(&plan, "lib").dynamic_link.clone().unwrap(),
+    )
+}
+
+#[test]
+#[cfg(target_os = "macos")]
+fn frameworks_are_linked_on_macos() {
+    let (binary, dynamic) = framework_links("frameworks-macos");
+    for link in [binary, dynamic] {
+        assert!(
+            link.windows(2).any(|args| args == ["-framework", "Cocoa"]),
+            "{:?}",
+            link
+        );
+    }
+}
+
+#[test]
+#[cfg(not(target_os = "macos"))]
+fn frameworks_are_ignored_elsewhere() {
+    let (binary, dynamic) = framework_links("frameworks-elsewhere");
+    for link in [binary, dynamic] {
+        assert!(
+            !link.iter().any(|arg| arg == "-framework" || arg == "Cocoa"),
+            "{:?}",
+            link
+        );
+    }
+}
+
+#[test]
+fn workspace_members_build_with_the_workspace_settings() {
+    let project = load_example("workspace").unwrap();
+    let dir = project.dir.clone();
+
+    let ignored = project
+        .warnings
+        .iter()
+        .filter_map(|warning| match warning {
+            Warning::IgnoredMemberSettings { path, keys } => Some((path.clone(), keys.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ignored,
+        [
+            (
+                dir.join("seeds/C.toml"),
+                vec!["project.build_dir".to_string(), "profile".to_string()]
+            ),
+            (
+                dir.join("planter/C.toml"),
+                vec!["project.default_targets".to_string()]
+            ),
+        ]
+    );
+
+    let plan = plan(&project, &Default::default());
+    let objects = plan
+        .iter()
+        .flat_map(|target| target.objects.iter())
+        .collect::<Vec<_>>();
+    assert_eq!(objects.len(), 3);
+    for object in objects {
+        assert!(
+            object.starts_with(dir.join("build")),
+            "{}",
+            object.display()
+        );
+    }
+
+    let planter = target_plan(&plan, "planter").dynamic_link.as_ref().unwrap();
+    assert!(planter.contains(&dir.join("seeds/libseeds.a").display().to_string()));
+    let garden = target_plan(&plan, "garden").binary_link.as_ref().unwrap();
+    assert!(garden.contains(&"-lplanter".to_string()), "{:?}", garden);
+}
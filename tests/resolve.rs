@@ -0,0 +1,336 @@
+mod common;
+
+use common::{load_example, TestProject};
+use cretaceous::{error::Error, project::Warning};
+
+#[test]
+fn external_source_is_denied_by_default() {
+    let outside = TestProject::new("external-denied-outside", "", &["ext.c"]);
+    let ext = outside.dir.join("ext.c");
+    let project = TestProject::new(
+        "external-denied",
+        &format!(
+            r#"
+            [project]
+            name = "external"
+            version = "1.0.0"
+
+            [target.app]
+            type = "binary"
+            path = "."
+            sources = ["main.c", {:?}]
+            "#,
+            ext
+        ),
+        &["main.c"],
+    );
+
+    assert_eq!(
+        project.load().unwrap_err(),
+        Error::ExternalPath(ext.display().to_string())
+    );
+}
+
+#[test]
+fn external_source_is_allowed_by_the_allowlist() {
+    let outside = TestProject::new("external-allowed-outside", "", &["ext.c"]);
+    let ext = outside.dir.join("ext.c");
+    let project = TestProject::new(
+        "external-allowed",
+        &format!(
+            r#"
+            [project]
+            name = "external"
+            version = "1.0.0"
+            allow_external_paths = [{:?}]
+
+            [target.app]
+            type = "binary"
+            path = "."
+            sources = ["main.c", {:?}]
+            "#,
+            outside.dir, ext
+        ),
+        &["main.c"],
+    );
+
+    let project = project.load().unwrap();
+    assert!(project.target["app"].sources.contains(&ext));
+}
+
+#[test]
+fn two_target_cycle_is_an_error() {
+    assert_eq!(
+        load_example("cycles/two").unwrap_err(),
+        Error::DependencyCycle {
+            path: vec!["a".into(), "b".into(), "a".into()]
+        }
+    );
+}
+
+#[test]
+fn three_target_cycle_is_an_error() {
+    assert_eq!(
+        load_example("cycles/three").unwrap_err(),
+        Error::DependencyCycle {
+            path: vec!["a".into(), "b".into(), "c".into(), "a".into()]
+        }
+    );
+}
+
+#[test]
+fn duplicate_source_is_compiled_once() {
+    let project = TestProject::new(
+        "duplicate-source",
+        r#"
+        [project]
+        name = "duplicate"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        path = "."
+        sources = ["main.c", "./main.c", "*.c"]
+        "#,
+        &["main.c", "util.c"],
+    );
+    let main = project.dir.join("main.c");
+    let util = project.dir.join("util.c");
+
+    let project = project.load().unwrap();
+    assert_eq!(project.target["app"].sources, [main.clone(), util]);
+    assert_eq!(project.warnings.len(), 2, "{:?}", project.warnings);
+    for warning in project.warnings.iter() {
+        assert!(
+            matches!(warning, Warning::DuplicateFile { target, path, .. } if target == "app" && *path == main),
+            "{:?}",
+            warning
+        );
+    }
+}
+
+#[test]
+fn build_order_follows_declaration_order() {
+    let project = TestProject::new(
+        "build-order",
+        r#"
+        [project]
+        name = "order"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        needs = ["zeta", "alpha"]
+        sources = ["main.c"]
+
+        [target.zeta]
+        type = "archive"
+        needs = "core"
+        sources = ["zeta.c"]
+
+        [target.alpha]
+        type = "archive"
+        needs = "core"
+        sources = ["alpha.c"]
+
+        [target.core]
+        type = "archive"
+        sources = ["core.c"]
+
+        [target.extra]
+        type = "archive"
+        sources = ["extra.c"]
+        "#,
+        &[
+            "app/main.c",
+            "zeta/zeta.c",
+            "alpha/alpha.c",
+            "core/core.c",
+            "extra/extra.c",
+        ],
+    );
+    let project = project.load().unwrap();
+
+    let order = project
+        .targets_in_order()
+        .unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    assert_eq!(order, ["core", "zeta", "alpha", "app", "extra"]);
+
+    let levels = project
+        .target_levels()
+        .unwrap()
+        .into_iter()
+        .map(|level| level.into_iter().map(|(name, _)| name).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        levels,
+        [vec!["core", "extra"], vec!["zeta", "alpha"], vec!["app"]]
+    );
+}
+
+#[test]
+fn target_for_another_platform_is_skipped() {
+    let other = if cfg!(target_os = "windows") {
+        "linux"
+    } else {
+        "windows"
+    };
+    let project = TestProject::new(
+        "platforms",
+        &format!(
+            r#"
+            [project]
+            name = "platforms"
+            version = "1.0.0"
+
+            [target.backend]
+            type = "archive"
+            sources = ["backend.c"]
+            platforms = [{:?}]
+
+            [target.native]
+            type = "archive"
+            sources = ["native.c"]
+            platforms = [{:?}]
+
+            [target.app]
+            type = "binary"
+            needs = ["backend", "native"]
+            sources = ["main.c"]
+            "#,
+            other,
+            std::env::consts::OS
+        ),
+        // backend's source doesn't exist, since it isn't resolved
+        &["native/native.c", "app/main.c"],
+    );
+
+    let project = project.load().unwrap();
+    assert!(!project.target.contains_key("backend"));
+    assert!(project.target.contains_key("native"));
+    assert_eq!(project.target["app"].needs, ["native"]);
+    assert!(project.warnings.iter().any(|warning| matches!(
+        warning,
+        Warning::SkippedTarget { target, platforms } if target == "backend" && platforms == &[other]
+    )));
+}
+
+#[test]
+fn sources_sharing_an_object_are_an_error() {
+    let project = TestProject::new(
+        "same-object",
+        r#"
+        [project]
+        name = "same"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        path = "."
+        sources = ["foo.c", "foo.cpp"]
+        "#,
+        &["foo.c", "foo.cpp"],
+    );
+
+    assert_eq!(
+        project.load().unwrap_err(),
+        Error::SameObject {
+            first: project.dir.join("foo.c").display().to_string(),
+            second: project.dir.join("foo.cpp").display().to_string(),
+        }
+    );
+}
+
+#[test]
+fn sources_with_one_name_in_different_directories_are_fine() {
+    let project = TestProject::new(
+        "same-name",
+        r#"
+        [project]
+        name = "same"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        path = "."
+        sources = ["a/foo.c", "b/foo.c"]
+        "#,
+        &["a/foo.c", "b/foo.c"],
+    );
+
+    assert_eq!(project.load().unwrap().target["app"].sources.len(), 2);
+}
+
+#[test]
+fn target_without_sources_is_an_error() {
+    let project = TestProject::new(
+        "no-sources",
+        r#"
+        [project]
+        name = "empty"
+        version = "1.0.0"
+
+        [target.app]
+        type = "binary"
+        path = "."
+        sources = []
+        "#,
+        &[],
+    );
+
+    assert_eq!(
+        project.load().unwrap_err(),
+        Error::InvalidProject(vec![Error::NoSources("app".into())])
+    );
+}
+
+/// The errors from loading a project whose only target has the given types
+fn target_type_errors(name: &str, types: &str) -> Vec<Error> {
+    let project = TestProject::new(
+        name,
+        &format!(
+            r#"
+            [project]
+            name = "types"
+            version = "1.0.0"
+
+            [target.lib]
+            type = {}
+            path = "."
+            sources = ["lib.c"]
+            "#,
+            types
+        ),
+        &["lib.c"],
+    );
+
+    match project.load().unwrap_err() {
+        Error::InvalidProject(errors) => errors,
+        err => panic!("expected InvalidProject, got {:?}", err),
+    }
+}
+
+#[test]
+fn binary_and_dynamic_target_is_an_error() {
+    assert_eq!(
+        target_type_errors("binary-dynamic", r#"["binary", "dynamic"]"#),
+        [Error::InvalidTarget {
+            name: "lib".into(),
+            why: "it can't be both a binary and a dynamic library".into(),
+        }]
+    );
+}
+
+#[test]
+fn repeated_target_type_is_an_error() {
+    assert_eq!(
+        target_type_errors("repeated-type", r#"["archive", "archive"]"#),
+        [Error::InvalidTarget {
+            name: "lib".into(),
+            why: "archive is listed more than once".into(),
+        }]
+    );
+}